@@ -0,0 +1,83 @@
+use crate::message::payload::{Encoded, Slice};
+use crate::message::Message;
+use crate::RelayError;
+use bytes::Bytes;
+use serde::Serialize;
+
+mod compact;
+
+/*
+
+    How the messages in relay frames are encoded. Every frame says which
+    codec its message was encoded with, and a node decodes all of them
+    whatever it was configured to send with. Peers that can't decode
+    everything, like embedded peers that can only afford the compact
+    encoding, get answered with the codec they sent with.
+
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    MessagePack,
+    // hand-rolled and dependency free, see `compact` for the layout
+    Compact,
+    Cbor,
+}
+
+impl Codec {
+    // what the frame header calls the codec, these can never change
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Codec::MessagePack => 0,
+            Codec::Compact => 1,
+            Codec::Cbor => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            0 => Some(Codec::MessagePack),
+            1 => Some(Codec::Compact),
+            2 => Some(Codec::Cbor),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn encode(self, message: &Message) -> Result<Bytes, RelayError> {
+        match self {
+            Codec::MessagePack => to_msgpack(message),
+            Codec::Compact => Ok(compact::encode(message)),
+            Codec::Cbor => to_cbor(message),
+        }
+    }
+
+    pub(crate) fn decode(self, bytes: Bytes) -> Result<Message, RelayError> {
+        match self {
+            // payloads are sliced out of `bytes` rather than copied
+            Codec::MessagePack => match rmp_serde::from_slice::<Encoded<Slice>>(&bytes) {
+                Ok(encoded) => Ok(encoded.into_message(|payload| payload.into_bytes(&bytes))),
+                Err(err) => Err(RelayError::Decode(err.to_string())),
+            },
+            Codec::Compact => compact::decode(bytes),
+            Codec::Cbor => match ciborium::de::from_reader(bytes.as_ref()) {
+                Ok(message) => Ok(message),
+                Err(err) => Err(RelayError::Decode(err.to_string())),
+            },
+        }
+    }
+}
+
+fn to_msgpack(message: &impl Serialize) -> Result<Bytes, RelayError> {
+    match rmp_serde::to_vec(message) {
+        Ok(bytes) => Ok(bytes.into()),
+        Err(err) => Err(RelayError::Encode(err.to_string())),
+    }
+}
+
+fn to_cbor(message: &impl Serialize) -> Result<Bytes, RelayError> {
+    let mut bytes = Vec::new();
+    match ciborium::ser::into_writer(message, &mut bytes) {
+        Ok(()) => Ok(bytes.into()),
+        Err(err) => Err(RelayError::Encode(err.to_string())),
+    }
+}
@@ -0,0 +1,329 @@
+use crate::message::{Message, PayloadMask};
+use crate::RelayError;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/*
+
+    A message is a one byte tag followed by its fields in declaration
+    order. Ids, page counts and keys are single bytes; payloads, protocol
+    ids and payload masks are a length followed by that many bytes; lists
+    are a count followed by their items. Lengths and counts are LEB128
+    varints, so anything under 128 takes a single byte. There is nothing
+    else: no field names, no padding, and a message has to use up its
+    frame exactly.
+
+*/
+
+const NEGOTIABLE_MESSAGE: u8 = 0;
+const NEGOTIATED_PROTOCOL_CHOICE: u8 = 1;
+const NEGOTIATION_FAILED: u8 = 2;
+const CONNECTION_ACCEPTED: u8 = 3;
+const CONNECTION_CONFIRMED: u8 = 4;
+const CONNECTION_CLOSED: u8 = 5;
+const CONNECTION_MESSAGE: u8 = 6;
+const CONNECTION_REQUESTED: u8 = 7;
+const PING: u8 = 8;
+const PONG: u8 = 9;
+const NEGOTIATION_ACKNOWLEDGED: u8 = 10;
+
+pub(super) fn encode(message: &Message) -> Bytes {
+    let mut buf = BytesMut::new();
+    match message {
+        Message::NegotiableMessage {
+            message_id,
+            page_count,
+            proposals,
+            payload_mask,
+            payload,
+        } => {
+            buf.put_u8(NEGOTIABLE_MESSAGE);
+            buf.put_u8(*message_id);
+            buf.put_u8(*page_count);
+            put_varint(&mut buf, proposals.len());
+            for proposal in proposals {
+                put_bytes(&mut buf, proposal);
+            }
+            put_bytes(&mut buf, payload_mask.as_bytes());
+            put_bytes(&mut buf, payload);
+        }
+        Message::NegotiatedProtocolChoice {
+            message_id,
+            proposal,
+        } => {
+            buf.put_u8(NEGOTIATED_PROTOCOL_CHOICE);
+            buf.put_u8(*message_id);
+            put_bytes(&mut buf, proposal);
+        }
+        Message::NegotiationFailed {
+            message_id,
+            page_count,
+        } => {
+            buf.put_u8(NEGOTIATION_FAILED);
+            buf.put_u8(*message_id);
+            buf.put_u8(*page_count);
+        }
+        Message::ConnectionAccepted {
+            protocol,
+            key,
+            payload,
+        } => {
+            buf.put_u8(CONNECTION_ACCEPTED);
+            put_bytes(&mut buf, protocol);
+            buf.put_u8(*key);
+            put_bytes(&mut buf, payload);
+        }
+        Message::ConnectionConfirmed {
+            protocol,
+            key,
+            payload,
+        } => {
+            buf.put_u8(CONNECTION_CONFIRMED);
+            put_bytes(&mut buf, protocol);
+            buf.put_u8(*key);
+            put_bytes(&mut buf, payload);
+        }
+        Message::ConnectionClosed { key, payload } => {
+            buf.put_u8(CONNECTION_CLOSED);
+            buf.put_u8(*key);
+            put_bytes(&mut buf, payload);
+        }
+        Message::ConnectionMessage { key, payload } => {
+            buf.put_u8(CONNECTION_MESSAGE);
+            buf.put_u8(*key);
+            put_bytes(&mut buf, payload);
+        }
+        Message::ConnectionRequested { protocol, payload } => {
+            buf.put_u8(CONNECTION_REQUESTED);
+            put_bytes(&mut buf, protocol);
+            put_bytes(&mut buf, payload);
+        }
+        Message::Ping => buf.put_u8(PING),
+        Message::Pong => buf.put_u8(PONG),
+        Message::NegotiationAcknowledged {
+            message_id,
+            proposal,
+        } => {
+            buf.put_u8(NEGOTIATION_ACKNOWLEDGED);
+            buf.put_u8(*message_id);
+            put_bytes(&mut buf, proposal);
+        }
+    }
+    buf.freeze()
+}
+
+pub(super) fn decode(mut bytes: Bytes) -> Result<Message, RelayError> {
+    let buf = &mut bytes;
+    let message = match get_u8(buf)? {
+        NEGOTIABLE_MESSAGE => {
+            let message_id = get_u8(buf)?;
+            let page_count = get_u8(buf)?;
+            let count = get_varint(buf)?;
+            // every proposal takes at least a byte, don't trust the count
+            // any further than that
+            let mut proposals = Vec::with_capacity(count.min(buf.remaining()));
+            for _ in 0..count {
+                proposals.push(get_bytes(buf)?.to_vec());
+            }
+            Message::NegotiableMessage {
+                message_id,
+                page_count,
+                proposals,
+                payload_mask: PayloadMask::from_bytes(get_bytes(buf)?.to_vec()),
+                payload: get_bytes(buf)?,
+            }
+        }
+        NEGOTIATED_PROTOCOL_CHOICE => Message::NegotiatedProtocolChoice {
+            message_id: get_u8(buf)?,
+            proposal: get_bytes(buf)?.to_vec(),
+        },
+        NEGOTIATION_FAILED => Message::NegotiationFailed {
+            message_id: get_u8(buf)?,
+            page_count: get_u8(buf)?,
+        },
+        CONNECTION_ACCEPTED => Message::ConnectionAccepted {
+            protocol: get_bytes(buf)?.to_vec(),
+            key: get_u8(buf)?,
+            payload: get_bytes(buf)?,
+        },
+        CONNECTION_CONFIRMED => Message::ConnectionConfirmed {
+            protocol: get_bytes(buf)?.to_vec(),
+            key: get_u8(buf)?,
+            payload: get_bytes(buf)?,
+        },
+        CONNECTION_CLOSED => Message::ConnectionClosed {
+            key: get_u8(buf)?,
+            payload: get_bytes(buf)?,
+        },
+        CONNECTION_MESSAGE => Message::ConnectionMessage {
+            key: get_u8(buf)?,
+            payload: get_bytes(buf)?,
+        },
+        CONNECTION_REQUESTED => Message::ConnectionRequested {
+            protocol: get_bytes(buf)?.to_vec(),
+            payload: get_bytes(buf)?,
+        },
+        PING => Message::Ping,
+        PONG => Message::Pong,
+        NEGOTIATION_ACKNOWLEDGED => Message::NegotiationAcknowledged {
+            message_id: get_u8(buf)?,
+            proposal: get_bytes(buf)?.to_vec(),
+        },
+        tag => return Err(RelayError::Decode(format!("unknown message tag {}", tag))),
+    };
+
+    if buf.has_remaining() {
+        return Err(RelayError::Decode(format!(
+            "{} trailing bytes",
+            buf.remaining()
+        )));
+    }
+    Ok(message)
+}
+
+fn put_varint(buf: &mut BytesMut, mut value: usize) {
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn put_bytes(buf: &mut BytesMut, bytes: &[u8]) {
+    put_varint(buf, bytes.len());
+    buf.put_slice(bytes);
+}
+
+fn get_u8(buf: &mut Bytes) -> Result<u8, RelayError> {
+    if !buf.has_remaining() {
+        return Err(RelayError::Decode("message is truncated".to_string()));
+    }
+    Ok(buf.get_u8())
+}
+
+fn get_varint(buf: &mut Bytes) -> Result<usize, RelayError> {
+    let mut value: usize = 0;
+    for shift in (0..usize::BITS).step_by(7) {
+        let byte = get_u8(buf)?;
+        value |= usize::from(byte & 0x7f)
+            .checked_shl(shift)
+            .filter(|shifted| shifted >> shift == usize::from(byte & 0x7f))
+            .ok_or_else(|| RelayError::Decode("length is too large".to_string()))?;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(RelayError::Decode("length is too large".to_string()))
+}
+
+// the bytes are a slice of the frame, not a copy
+fn get_bytes(buf: &mut Bytes) -> Result<Bytes, RelayError> {
+    let len = get_varint(buf)?;
+    if len > buf.remaining() {
+        return Err(RelayError::Decode("message is truncated".to_string()));
+    }
+    Ok(buf.split_to(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages() -> Vec<Message> {
+        let mut payload_mask = PayloadMask::new();
+        payload_mask.set(9);
+        vec![
+            Message::NegotiableMessage {
+                message_id: 1,
+                page_count: 2,
+                proposals: vec![b"relay/1".to_vec(), vec![0; 200]],
+                payload_mask,
+                payload: Bytes::from(vec![7; 300]),
+            },
+            Message::NegotiatedProtocolChoice {
+                message_id: 1,
+                proposal: b"relay/1".to_vec(),
+            },
+            Message::NegotiationFailed {
+                message_id: 1,
+                page_count: 2,
+            },
+            Message::ConnectionAccepted {
+                protocol: b"relay/1".to_vec(),
+                key: 3,
+                payload: Bytes::from_static(b"accepted"),
+            },
+            Message::ConnectionConfirmed {
+                protocol: b"relay/1".to_vec(),
+                key: 3,
+                payload: Bytes::new(),
+            },
+            Message::ConnectionClosed {
+                key: 3,
+                payload: Bytes::from_static(b"closed"),
+            },
+            Message::ConnectionMessage {
+                key: 3,
+                payload: Bytes::from_static(b"message"),
+            },
+            Message::ConnectionRequested {
+                protocol: b"relay/1".to_vec(),
+                payload: Bytes::from_static(b"requested"),
+            },
+            Message::Ping,
+            Message::Pong,
+            Message::NegotiationAcknowledged {
+                message_id: 1,
+                proposal: b"relay/1".to_vec(),
+            },
+        ]
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        for message in messages() {
+            let encoded = encode(&message);
+            let decoded = decode(encoded.clone()).unwrap();
+            // messages don't compare, their encodings do
+            assert_eq!(encode(&decoded), encoded);
+        }
+    }
+
+    #[test]
+    fn truncated_and_padded_messages_are_rejected() {
+        for message in messages() {
+            let encoded = encode(&message);
+            for len in 0..encoded.len() {
+                assert!(
+                    matches!(decode(encoded.slice(..len)), Err(RelayError::Decode(_))),
+                    "{:?} decoded after truncating to {} bytes",
+                    encoded,
+                    len
+                );
+            }
+            let mut padded = BytesMut::from(&encoded[..]);
+            padded.put_u8(0);
+            assert!(matches!(
+                decode(padded.freeze()),
+                Err(RelayError::Decode(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn lengths_that_overflow_are_rejected() {
+        let mut frame = BytesMut::new();
+        frame.put_u8(CONNECTION_MESSAGE);
+        frame.put_u8(3);
+        frame.put_slice(&[0xff; 11]);
+        frame.put_u8(0x01);
+        assert!(matches!(decode(frame.freeze()), Err(RelayError::Decode(_))));
+    }
+
+    #[test]
+    fn unknown_tags_are_rejected() {
+        assert!(matches!(
+            decode(Bytes::from_static(&[0xff])),
+            Err(RelayError::Decode(_))
+        ));
+    }
+}
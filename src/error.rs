@@ -0,0 +1,80 @@
+use crate::message::{ProtocolId, ProtocolKey};
+use crate::transport::PeerAddress;
+use crate::wire::WireVersion;
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum RelayError {
+    Io(io::Error),
+    Decode(String),
+    Encode(String),
+    UnknownProtocolKey(ProtocolKey),
+    UnknownProtocolId(ProtocolId),
+    KeysExhausted,
+    MessageIdsExhausted(PeerAddress),
+    NotConnected(PeerAddress),
+    TransportClosed,
+    UnsupportedAddress(PeerAddress),
+    UnsupportedDelivery(PeerAddress),
+    DuplicateScheme(String),
+    QueueFull(PeerAddress),
+    FrameTooLarge(usize),
+    Encryption(String),
+    UnsupportedVersion(WireVersion),
+    VerificationRejected,
+    NodeStopped,
+    InvalidConfig(String),
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelayError::Io(err) => write!(f, "io error: {}", err),
+            RelayError::Decode(err) => write!(f, "failed to deserialize bytes: {}", err),
+            RelayError::Encode(err) => write!(f, "failed to serialize message: {}", err),
+            RelayError::UnknownProtocolKey(key) => write!(f, "unknown protocol key {}", key),
+            RelayError::UnknownProtocolId(id) => write!(f, "unknown protocol id {:?}", id),
+            RelayError::KeysExhausted => write!(f, "no protocol keys left to assign"),
+            RelayError::MessageIdsExhausted(address) => {
+                write!(f, "every negotiation id for {:?} is in use", address)
+            }
+            RelayError::NotConnected(address) => write!(f, "not connected to {:?}", address),
+            RelayError::TransportClosed => write!(f, "transport is closed"),
+            RelayError::UnsupportedAddress(address) => {
+                write!(f, "unsupported address {:?}", address)
+            }
+            RelayError::UnsupportedDelivery(address) => {
+                write!(f, "reliable delivery to {:?} is not supported", address)
+            }
+            RelayError::DuplicateScheme(scheme) => {
+                write!(f, "more than one transport for scheme {:?}", scheme)
+            }
+            RelayError::QueueFull(address) => write!(f, "queue to {:?} is full", address),
+            RelayError::FrameTooLarge(len) => {
+                write!(f, "a {} byte frame is too large for its transport", len)
+            }
+            RelayError::Encryption(err) => write!(f, "encryption error: {}", err),
+            RelayError::UnsupportedVersion(version) => {
+                write!(f, "unsupported wire version {}", version)
+            }
+            RelayError::VerificationRejected => write!(f, "protocol rejected verification"),
+            RelayError::NodeStopped => write!(f, "node is no longer running"),
+            RelayError::InvalidConfig(err) => write!(f, "invalid configuration: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RelayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RelayError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RelayError {
+    fn from(err: io::Error) -> Self {
+        RelayError::Io(err)
+    }
+}
@@ -0,0 +1,83 @@
+use crate::peer::PeerId;
+use crate::transport::PeerAddress;
+use futures::future;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Config {
+    pub(crate) interval: Duration,
+    // always longer than the interval, see `NodeBuilder::build`
+    pub(crate) timeout: Duration,
+}
+
+/*
+
+    Any relay message counts as a sign of life, so busy peers are never
+    pinged. Every interval we look at the peers some protocol is connected
+    to: those we haven't heard from for an interval get a ping, which they
+    answer with a pong, and those we haven't heard from for the whole
+    timeout are given up on. Pings are best effort on datagram transports,
+    a lost one is made up for on the next interval.
+
+*/
+pub(crate) struct Heartbeats {
+    config: Config,
+    ticker: Interval,
+    heard: HashMap<PeerId, Instant>,
+}
+
+// what to do about the peers we haven't heard from in a while
+#[derive(Default)]
+pub(crate) struct Silent {
+    pub(crate) ping: Vec<PeerAddress>,
+    pub(crate) expired: Vec<PeerId>,
+}
+
+impl Heartbeats {
+    pub(crate) fn new(config: Config) -> Heartbeats {
+        let mut ticker = time::interval_at(Instant::now() + config.interval, config.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Heartbeats {
+            config,
+            ticker,
+            heard: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn heard(&mut self, peer: &PeerId) {
+        self.heard.insert(peer.clone(), Instant::now());
+    }
+
+    pub(crate) fn forget(&mut self, peer: &PeerId) {
+        self.heard.remove(peer);
+    }
+
+    pub(crate) fn check(&mut self, peers: &HashMap<PeerId, PeerAddress>) -> Silent {
+        let now = Instant::now();
+        self.heard.retain(|peer, _| peers.contains_key(peer));
+
+        let mut silent = Silent::default();
+        for (peer, address) in peers {
+            let heard = *self.heard.entry(peer.clone()).or_insert(now);
+            let quiet = now.saturating_duration_since(heard);
+            if quiet >= self.config.timeout {
+                silent.expired.push(peer.clone());
+            } else if quiet >= self.config.interval {
+                silent.ping.push(address.clone());
+            }
+        }
+        silent
+    }
+}
+
+// resolves every interval, or never without heartbeats
+pub(crate) async fn tick(heartbeats: &mut Option<Heartbeats>) {
+    match heartbeats {
+        Some(heartbeats) => {
+            heartbeats.ticker.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
@@ -1,9 +1,26 @@
+pub use codec::Codec;
+pub use error::RelayError;
 pub use message::{Message, MessageId, PageCount, Payload, PayloadMask, ProtocolId, ProtocolKey};
-pub use node::{Delegate, Node};
-pub use protocol::Handler as ProtocolHandler;
-pub use transport::{PeerAddress, TransportProtocol};
+pub use negotiation::{NegotiationOutcome, NegotiationRx, Proposal};
+pub use node::{AsyncDelegate, Delegate, Node, NodeBuilder, NodeHandle};
+pub use noise::{Keypair, PublicKey};
+pub use peer::PeerId;
+pub use protocol::{AsyncHandler as AsyncProtocolHandler, Handler as ProtocolHandler};
+pub use transport::{
+    memory::{MemoryNetwork, MemoryTransport},
+    Delivery, Message as TransportMessage, Overflow, PeerAddress, Transport, TransportEvent,
+    TransportEvents, TransportProtocol,
+};
+pub use wire::{WireVersion, MIN_WIRE_VERSION, WIRE_VERSION};
 
+mod codec;
+mod error;
+mod heartbeat;
 mod message;
+mod negotiation;
 mod node;
+mod noise;
+mod peer;
 mod protocol;
 mod transport;
+mod wire;
@@ -1,22 +1,117 @@
-use crate::{Node, PeerAddress};
+use crate::{Node, PeerAddress, PeerId, RelayError};
 use bytes::Bytes;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
 
 pub mod connection_accepted;
 pub mod connection_closed;
 pub mod connection_confirmed;
 pub mod connection_message;
+pub mod connection_requested;
 pub mod negotiable_message;
 pub mod negotiated_protocol_choice;
+pub mod negotiation_acknowledged;
 pub mod negotiation_failed;
+pub(crate) mod payload;
 
 // define types
 pub type MessageId = u8;
 pub type PageCount = u8;
 pub type ProtocolId = Vec<u8>;
 pub type ProtocolKey = u8;
-pub type Payload = Vec<u8>;
-pub type PayloadMask = u8;
+
+// what handling a message resolves to: an optional reply for the sender
+pub(crate) type Response = Result<Option<Message>, RelayError>;
+// payloads handed to handlers share the buffer their frame arrived in
+pub type Payload = Bytes;
+
+// bitset marking which proposals the inline payload belongs to, serialized as
+// a byte string with no trailing zero bytes so small masks stay small
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PayloadMask(Vec<u8>);
+impl PayloadMask {
+    pub fn new() -> PayloadMask {
+        PayloadMask(Vec::new())
+    }
+
+    pub fn set(&mut self, index: usize) {
+        let byte = index / 8;
+        if byte >= self.0.len() {
+            self.0.resize(byte + 1, 0);
+        }
+        self.0[byte] |= 1 << (index % 8);
+    }
+
+    pub fn is_set(&self, index: usize) -> bool {
+        match self.0.get(index / 8) {
+            Some(byte) => byte & (1 << (index % 8)) != 0,
+            None => false,
+        }
+    }
+
+    pub(crate) fn from_bytes(bytes: Vec<u8>) -> PayloadMask {
+        PayloadMask(bytes)
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+impl FromIterator<bool> for PayloadMask {
+    fn from_iter<I: IntoIterator<Item = bool>>(iter: I) -> Self {
+        let mut mask = PayloadMask::new();
+        for (index, set) in iter.into_iter().enumerate() {
+            if set {
+                mask.set(index);
+            }
+        }
+        mask
+    }
+}
+impl Serialize for PayloadMask {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+impl<'de> Deserialize<'de> for PayloadMask {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_bytes(PayloadMaskVisitor)
+    }
+}
+
+struct PayloadMaskVisitor;
+impl<'de> de::Visitor<'de> for PayloadMaskVisitor {
+    type Value = PayloadMask;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a payload mask byte string")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+        Ok(PayloadMask(bytes.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(PayloadMask(bytes))
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::new();
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(PayloadMask(bytes))
+    }
+
+    // peers that predate variable length masks send a single u8
+    fn visit_u64<E: de::Error>(self, mask: u64) -> Result<Self::Value, E> {
+        match u8::try_from(mask) {
+            Ok(0) => Ok(PayloadMask::new()),
+            Ok(mask) => Ok(PayloadMask(vec![mask])),
+            Err(_) => Err(E::invalid_value(de::Unexpected::Unsigned(mask), &self)),
+        }
+    }
+}
 
 // relay protocol messages
 #[derive(Serialize, Deserialize)]
@@ -26,6 +121,7 @@ pub enum Message {
         page_count: PageCount,
         proposals: Vec<ProtocolId>,
         payload_mask: PayloadMask,
+        #[serde(with = "payload")]
         payload: Payload,
     },
     NegotiatedProtocolChoice {
@@ -39,44 +135,64 @@ pub enum Message {
     ConnectionAccepted {
         protocol: ProtocolId,
         key: ProtocolKey,
+        #[serde(with = "payload")]
         payload: Payload,
     },
     ConnectionConfirmed {
         protocol: ProtocolId,
         key: ProtocolKey,
+        #[serde(with = "payload")]
         payload: Payload,
     },
     ConnectionClosed {
         key: ProtocolKey,
+        #[serde(with = "payload")]
         payload: Payload,
     },
     ConnectionMessage {
         key: ProtocolKey,
+        #[serde(with = "payload")]
         payload: Payload,
     },
+    ConnectionRequested {
+        protocol: ProtocolId,
+        #[serde(with = "payload")]
+        payload: Payload,
+    },
+    // keep-alives for peers that have gone quiet
+    Ping,
+    Pong,
+    // the receiver of a negotiable message took the payload that came with
+    // the protocol it chose, and needs nothing more
+    NegotiationAcknowledged {
+        message_id: MessageId,
+        proposal: ProtocolId,
+    },
 }
-impl TryFrom<Bytes> for Message {
-    type Error = String;
-
-    fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
-        match rmp_serde::from_slice(bytes.as_ref()) {
-            Ok(msg) => Ok(msg),
-            Err(err) => Err(format!("failed to deserialize bytes: {}", err)),
-        }
-    }
-}
-impl TryFrom<Message> for Bytes {
-    type Error = String;
-
-    fn try_from(message: Message) -> Result<Self, Self::Error> {
-        match rmp_serde::to_vec(&message) {
-            Ok(bytes) => Ok(bytes.into()),
-            Err(err) => Err(format!("failed to serialize message: {}", err)),
+impl Message {
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Message::NegotiableMessage { .. } => "NegotiableMessage",
+            Message::NegotiatedProtocolChoice { .. } => "NegotiatedProtocolChoice",
+            Message::NegotiationFailed { .. } => "NegotiationFailed",
+            Message::ConnectionAccepted { .. } => "ConnectionAccepted",
+            Message::ConnectionConfirmed { .. } => "ConnectionConfirmed",
+            Message::ConnectionClosed { .. } => "ConnectionClosed",
+            Message::ConnectionMessage { .. } => "ConnectionMessage",
+            Message::ConnectionRequested { .. } => "ConnectionRequested",
+            Message::Ping => "Ping",
+            Message::Pong => "Pong",
+            Message::NegotiationAcknowledged { .. } => "NegotiationAcknowledged",
         }
     }
 }
 
-pub fn handle(node: &mut Node, address: PeerAddress, message: Message) {
+pub fn handle(
+    node: &mut Node,
+    peer: PeerId,
+    address: PeerAddress,
+    message: Message,
+) -> Result<(), RelayError> {
     let return_address = address.clone();
     let response = match message {
         Message::NegotiableMessage {
@@ -85,15 +201,23 @@ pub fn handle(node: &mut Node, address: PeerAddress, message: Message) {
             proposals,
             payload_mask,
             payload,
-        } => negotiable_message::handle(
-            node,
-            address,
-            message_id,
-            page_count,
-            proposals,
-            payload_mask,
-            payload,
-        ),
+        } => {
+            let choice = negotiable_message::handle(
+                node,
+                peer,
+                address,
+                message_id,
+                proposals,
+                payload_mask,
+                payload,
+            )?;
+            // none of the proposals on this page are supported, negotation
+            // failed; echo the page back so the sender can move on to the next one
+            Ok(choice.or(Some(Message::NegotiationFailed {
+                message_id,
+                page_count,
+            })))
+        }
         Message::NegotiatedProtocolChoice {
             message_id,
             proposal,
@@ -106,21 +230,83 @@ pub fn handle(node: &mut Node, address: PeerAddress, message: Message) {
             protocol,
             key,
             payload,
-        } => connection_accepted::handle(node, address, protocol, key, payload),
+        } => connection_accepted::handle(node, peer, address, protocol, key, payload),
         Message::ConnectionConfirmed {
             protocol,
             key,
             payload,
-        } => connection_confirmed::handle(node, address, protocol, key, payload),
+        } => connection_confirmed::handle(node, peer, address, protocol, key, payload),
         Message::ConnectionClosed { key, payload } => {
-            connection_closed::handle(node, address, key, payload)
+            connection_closed::handle(node, peer, address, key, payload)
         }
         Message::ConnectionMessage { key, payload } => {
-            connection_message::handle(node, address, key, payload)
+            connection_message::handle(node, peer, address, key, payload)
         }
+        Message::ConnectionRequested { protocol, payload } => {
+            connection_requested::handle(node, peer, address, protocol, payload)
+        }
+        Message::Ping => Ok(Some(Message::Pong)), // let the peer know we're still here
+        Message::Pong => Ok(None),                // hearing back was all we needed
+        Message::NegotiationAcknowledged {
+            message_id,
+            proposal,
+        } => negotiation_acknowledged::handle(node, address, message_id, proposal),
     };
 
-    if let Some(message) = response {
-        node.send(return_address, message);
+    if let Some(message) = response? {
+        node.send(return_address, message)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_masks_grow_past_eight_proposals() {
+        let mask: PayloadMask = (0..20).map(|index| index % 9 == 0).collect();
+        for index in 0..40 {
+            assert_eq!(mask.is_set(index), index < 20 && index % 9 == 0);
+        }
+        // only as many bytes as the highest set bit needs
+        assert_eq!(mask.as_bytes(), [0b0000_0001, 0b0000_0010, 0b0000_0100]);
+    }
+
+    #[test]
+    fn payload_masks_serialize_as_byte_strings() {
+        let mut mask = PayloadMask::new();
+        mask.set(3);
+        mask.set(12);
+
+        let bytes = rmp_serde::to_vec(&mask).unwrap();
+        assert_eq!(bytes, [0xc4, 2, 0b0000_1000, 0b0001_0000]);
+        assert_eq!(rmp_serde::from_slice::<PayloadMask>(&bytes).unwrap(), mask);
+
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&mask, &mut bytes).unwrap();
+        assert_eq!(
+            ciborium::de::from_reader::<PayloadMask, _>(&bytes[..]).unwrap(),
+            mask
+        );
+    }
+
+    #[test]
+    fn payload_masks_from_older_peers_decode_from_a_u8() {
+        let legacy = rmp_serde::to_vec(&0b0000_0101u8).unwrap();
+        let mask = rmp_serde::from_slice::<PayloadMask>(&legacy).unwrap();
+        assert_eq!(mask.as_bytes(), [0b0000_0101]);
+        assert!(mask.is_set(0) && mask.is_set(2) && !mask.is_set(1));
+
+        let empty = rmp_serde::to_vec(&0u8).unwrap();
+        assert_eq!(
+            rmp_serde::from_slice::<PayloadMask>(&empty).unwrap(),
+            PayloadMask::new()
+        );
+
+        // a u8 mask never had more than eight bits
+        let wide = rmp_serde::to_vec(&0x100u16).unwrap();
+        assert!(rmp_serde::from_slice::<PayloadMask>(&wide).is_err());
     }
 }
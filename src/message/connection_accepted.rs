@@ -1,5 +1,6 @@
-use crate::message::{Payload, ProtocolId, ProtocolKey};
-use crate::{Message, Node, PeerAddress};
+use crate::message::{Payload, ProtocolId, ProtocolKey, Response};
+use crate::{Message, Node, PeerAddress, PeerId, RelayError};
+use tracing::debug;
 
 /*
 
@@ -14,40 +15,70 @@ use crate::{Message, Node, PeerAddress};
 */
 pub fn handle(
     node: &mut Node,
+    peer: PeerId,
     address: PeerAddress,
     protocol_id: ProtocolId,
     peer_key: ProtocolKey,
     payload: Payload,
-) -> Option<Message> {
-    // verify the payload with the protocol (also get its key)
-    let (verification_payload, my_key) = match node.get_protocol(&protocol_id) {
-        None => return None, // invalid protocol id
-        Some(p) => {
-            let key = p.key;
-            let verification_payload = p
-                .handler
-                .verify_accepted_connection(address.clone(), payload);
-
-            (verification_payload, key)
-        }
+) -> Response {
+    // verify the payload with the protocol
+    let verification = match node.get_protocol(&protocol_id) {
+        None => return Err(RelayError::UnknownProtocolId(protocol_id)), // invalid protocol id
+        Some(p) => p
+            .handler
+            .verify_accepted_connection(peer.clone(), address.clone(), payload),
     };
 
+    // confirm once the protocol has made up its mind
+    let peer_address = address.clone();
+    node.defer(
+        peer.clone(),
+        address,
+        verification,
+        move |node, verification_payload| {
+            confirm(
+                node,
+                peer,
+                peer_address,
+                protocol_id,
+                peer_key,
+                verification_payload,
+            )
+        },
+    );
+
+    Ok(None)
+}
+
+fn confirm(
+    node: &mut Node,
+    peer: PeerId,
+    address: PeerAddress,
+    protocol_id: ProtocolId,
+    peer_key: ProtocolKey,
+    verification_payload: Option<Payload>,
+) -> Response {
     // if verification was successful, it should be "Some"
     let payload = match verification_payload {
+        None => return Err(RelayError::VerificationRejected), // connection denied
         Some(payload) => payload,
-        None => return None, // connection denied
     };
 
-    // insert peer key into protocol's peer_keys table
-    match node.get_protocol_mut(&protocol_id) {
-        None => return None, // invalid protocol id
-        Some(p) => p.peer_keys.insert(address, peer_key),
+    // insert peer key into protocol's peer_keys table (also get our key)
+    let my_key = match node.get_protocol_mut(&protocol_id) {
+        None => return Err(RelayError::UnknownProtocolId(protocol_id)), // invalid protocol id
+        Some(p) => {
+            debug!(?peer, ?address, protocol_id = ?protocol_id, peer_key, "connection established");
+            p.peer_keys.insert(peer.clone(), peer_key);
+            p.key
+        }
     };
+    node.set_peer_address(peer, address);
 
     // everything worked out, return our key
-    Some(Message::ConnectionConfirmed {
+    Ok(Some(Message::ConnectionConfirmed {
         protocol: protocol_id,
         key: my_key,
         payload,
-    })
+    }))
 }
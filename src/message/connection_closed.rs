@@ -1,40 +1,62 @@
-use crate::message::{Payload, ProtocolKey};
-use crate::{Message, Node, PeerAddress};
+use crate::message::{Payload, ProtocolId, ProtocolKey, Response};
+use crate::{Node, PeerAddress, PeerId, RelayError};
+use tracing::debug;
 
 /*
 
     If we are receiving this message, then a peer we have a connection with
     is asking to close the connection. We need to let the protocol validate
-    the request, and if so, we remove that address from the protocol's peer
-    keys table.
+    the request, and if so, we remove that peer from the protocol's peer
+    keys table. Peers that aren't in that table have nothing to close.
 
 */
 pub fn handle(
     node: &mut Node,
+    peer: PeerId,
     address: PeerAddress,
     key: ProtocolKey,
     payload: Payload,
-) -> Option<Message> {
+) -> Response {
     // get the id from the key
     let id = match node.get_protocol_id(key) {
-        None => return None, // invalid protocol key
+        None => return Err(RelayError::UnknownProtocolKey(key)), // invalid protocol key
         Some(id) => id.clone(),
     };
 
     // ask the protocol to verify this message
-    let verified = match node.get_protocol(&id) {
-        None => return None, // invalid protocol id
-        Some(p) => p.handler.verify_closed_connection(address.clone(), payload),
+    let verification = match node.get_protocol(&id) {
+        None => return Err(RelayError::UnknownProtocolId(id)), // invalid protocol id
+        Some(p) if !p.peer_keys.contains_key(&peer) => {
+            return Err(RelayError::NotConnected(address)); // no connection with the peer
+        }
+        Some(p) => p
+            .handler
+            .verify_closed_connection(peer.clone(), address.clone(), payload),
     };
+
+    // close once the protocol has made up its mind
+    node.defer(
+        peer.clone(),
+        address,
+        verification,
+        move |node, verified| close(node, peer, id, verified),
+    );
+
+    Ok(None)
+}
+
+fn close(node: &mut Node, peer: PeerId, id: ProtocolId, verified: bool) -> Response {
     if !verified {
-        return None; // failed protocol verification
+        return Err(RelayError::VerificationRejected); // failed protocol verification
     }
 
-    // remove (addr/peer_key) from protocol's peer_keys table
+    // remove (peer/peer_key) from protocol's peer_keys table
+    debug!(?peer, protocol_id = ?id, "connection closed");
     match node.get_protocol_mut(&id) {
-        None => return None, // invalid protocol id
-        Some(p) => p.peer_keys.remove(&address),
+        None => return Err(RelayError::UnknownProtocolId(id)), // invalid protocol id
+        Some(p) => p.peer_keys.remove(&peer),
     };
+    node.forget_peer(&peer);
 
-    None
+    Ok(None)
 }
@@ -1,5 +1,6 @@
-use crate::message::{Payload, ProtocolId, ProtocolKey};
-use crate::{Message, Node, PeerAddress};
+use crate::message::{Payload, ProtocolId, ProtocolKey, Response};
+use crate::{Node, PeerAddress, PeerId, RelayError};
+use tracing::debug;
 
 /*
 
@@ -12,28 +13,52 @@ use crate::{Message, Node, PeerAddress};
 */
 pub fn handle(
     node: &mut Node,
+    peer: PeerId,
     address: PeerAddress,
     protocol_id: ProtocolId,
     peer_key: ProtocolKey,
     payload: Payload,
-) -> Option<Message> {
+) -> Response {
     // ask the protocol to verify this message
-    match node.get_protocol(&protocol_id) {
-        None => return None, // invalid protocol id
-        Some(p) => {
-            let address = address.clone();
-            if !p.handler.verify_confirmed_connection(address, payload) {
-                return None; // failed protocol verification
-            };
-        }
+    let verification = match node.get_protocol(&protocol_id) {
+        None => return Err(RelayError::UnknownProtocolId(protocol_id)), // invalid protocol id
+        Some(p) => p
+            .handler
+            .verify_confirmed_connection(peer.clone(), address.clone(), payload),
     };
 
+    // record the key once the protocol has made up its mind
+    let peer_address = address.clone();
+    node.defer(
+        peer.clone(),
+        address,
+        verification,
+        move |node, verified| establish(node, peer, peer_address, protocol_id, peer_key, verified),
+    );
+
+    Ok(None)
+}
+
+fn establish(
+    node: &mut Node,
+    peer: PeerId,
+    address: PeerAddress,
+    protocol_id: ProtocolId,
+    peer_key: ProtocolKey,
+    verified: bool,
+) -> Response {
+    if !verified {
+        return Err(RelayError::VerificationRejected); // failed protocol verification
+    }
+
     // insert peer key into protocol's peer_keys table
+    debug!(?peer, ?address, protocol_id = ?protocol_id, peer_key, "connection established");
     match node.get_protocol_mut(&protocol_id) {
-        None => return None, // invalid protocol id
-        Some(p) => p.peer_keys.insert(address, peer_key),
+        None => return Err(RelayError::UnknownProtocolId(protocol_id)), // invalid protocol id
+        Some(p) => p.peer_keys.insert(peer.clone(), peer_key),
     };
+    node.set_peer_address(peer, address);
 
     // everything worked out, no further work
-    None
+    Ok(None)
 }
@@ -1,30 +1,36 @@
-use crate::message::{Payload, ProtocolKey};
-use crate::{Message, Node, PeerAddress};
+use crate::message::{Payload, ProtocolKey, Response};
+use crate::{Node, PeerAddress, PeerId, RelayError};
 
 /*
 
     If we're getting this message, it means we have an established
     connection with this address, and that we should forward it to
-    the given protocol.
+    the given protocol. Peers the protocol has no connection with are
+    ignored, knowing its key isn't enough.
 
 */
 pub fn handle(
-    node: &Node,
+    node: &mut Node,
+    peer: PeerId,
     address: PeerAddress,
     key: ProtocolKey,
     payload: Payload,
-) -> Option<Message> {
+) -> Response {
     // get id from the key
     let id = match node.get_protocol_id(key) {
-        None => return None, // invalid protocol key
+        None => return Err(RelayError::UnknownProtocolKey(key)), // invalid protocol key
         Some(id) => id,
     };
 
     // relay the message
-    match node.get_protocol(&id) {
-        None => return None, // invalid protocol id
-        Some(p) => p.handler.handle_message(address, payload),
+    let handling = match node.get_protocol(id) {
+        None => return Err(RelayError::UnknownProtocolId(id.clone())), // invalid protocol id
+        Some(p) if !p.peer_keys.contains_key(&peer) => {
+            return Err(RelayError::NotConnected(address)); // no connection with the peer
+        }
+        Some(p) => p.handler.handle_message(peer, address, payload),
     };
+    node.drive(handling);
 
-    None
+    Ok(None)
 }
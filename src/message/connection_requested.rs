@@ -0,0 +1,58 @@
+use crate::message::{Payload, ProtocolId, Response};
+use crate::{Message, Node, PeerAddress, PeerId, RelayError};
+
+/*
+
+    If we are receiving this message, someone wants to open a connection
+    with one of our protocols. We should let the specified protocol verify
+    the request, and if it agrees, respond with a "Connection Accepted"
+    message that tells the requester what *our* shorthand key is, along
+    with the payload the protocol handed back to us.
+
+*/
+pub fn handle(
+    node: &mut Node,
+    peer: PeerId,
+    address: PeerAddress,
+    protocol_id: ProtocolId,
+    payload: Payload,
+) -> Response {
+    // ask the protocol to verify the request
+    let verification = match node.get_protocol(&protocol_id) {
+        None => return Err(RelayError::UnknownProtocolId(protocol_id)), // invalid protocol id
+        Some(p) => p
+            .handler
+            .verify_requested_connection(peer.clone(), address.clone(), payload),
+    };
+
+    // accept once the protocol has made up its mind
+    node.defer(
+        peer,
+        address,
+        verification,
+        move |node, verification_payload| accept(node, protocol_id, verification_payload),
+    );
+
+    Ok(None)
+}
+
+fn accept(node: &Node, protocol_id: ProtocolId, verification_payload: Option<Payload>) -> Response {
+    // if verification was successful, it should be "Some"
+    let payload = match verification_payload {
+        None => return Err(RelayError::VerificationRejected), // connection denied
+        Some(payload) => payload,
+    };
+
+    // get our key, the protocol may have gone away while verifying
+    let my_key = match node.get_protocol(&protocol_id) {
+        None => return Err(RelayError::UnknownProtocolId(protocol_id)), // invalid protocol id
+        Some(p) => p.key,
+    };
+
+    // let the requester know our key
+    Ok(Some(Message::ConnectionAccepted {
+        protocol: protocol_id,
+        key: my_key,
+        payload,
+    }))
+}
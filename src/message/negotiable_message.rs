@@ -1,15 +1,25 @@
-use super::{Message, MessageId, PageCount, Payload, PayloadMask, ProtocolId};
-use crate::{Node, PeerAddress};
+use super::{Message, MessageId, Payload, PayloadMask, ProtocolId, Response};
+use crate::{Node, PeerAddress, PeerId};
 
+/*
+
+    If we are receiving this message, a peer wants to talk to us over one of
+    the proposed protocols. We pick the first one we support. If the payload
+    was meant for that protocol we relay it and acknowledge it, otherwise
+    we tell the peer our choice so it can send the right payload.
+    If we support none of them there is nothing to say here; the caller
+    answers with the failure.
+
+*/
 pub fn handle(
-    node: &Node,
+    node: &mut Node,
+    peer: PeerId,
     address: PeerAddress,
     message_id: MessageId,
-    page_count: PageCount,
     proposals: Vec<ProtocolId>,
     payload_mask: PayloadMask,
     payload: Payload,
-) -> Option<Message> {
+) -> Response {
     // iterate through all the proposals
     for (index, id) in proposals.iter().enumerate() {
         // check to see if we have this protocol
@@ -19,29 +29,22 @@ pub fn handle(
         };
 
         // we support the protocol, but need a different payload
-        if !is_mask_bit_set(payload_mask, index) {
+        if !payload_mask.is_set(index) {
             let proposal = id.clone();
-            return Some(Message::NegotiatedProtocolChoice {
+            return Ok(Some(Message::NegotiatedProtocolChoice {
                 message_id,
                 proposal,
-            });
+            }));
         }
 
-        // we support the protocol and the payload: relay it
-        protocol.handler.handle_message(address, payload);
-        return None;
+        // we support the protocol and the payload: relay it and acknowledge
+        let handling = protocol.handler.handle_message(peer, address, payload);
+        node.drive(handling);
+        return Ok(Some(Message::NegotiationAcknowledged {
+            message_id,
+            proposal: id.clone(),
+        }));
     }
 
-    // none of the proposals are supported, negotation failed
-    return Some(Message::NegotiationFailed {
-        message_id,
-        page_count,
-    });
-}
-
-fn is_mask_bit_set(mask: PayloadMask, index: usize) -> bool {
-    match index < 8 {
-        true => (1 << index) & mask != 0,
-        false => false,
-    }
+    Ok(None)
 }
@@ -1,21 +1,30 @@
-use super::{MessageId, ProtocolId};
-use crate::{Message, Node, PeerAddress};
+use super::{MessageId, ProtocolId, Response};
+use crate::{Node, PeerAddress};
 
 /*
     If we are receiving this message, we need to make sure that we recently
-    sent a negotiable message with that message id. This is handled by the
-    delegate for the relay node.
+    sent a negotiable message with that message id. If the node sent it for
+    us, it knows which payload goes with the chosen protocol and follows up
+    on its own. Otherwise this is handled by the delegate for the relay node.
 
 */
 pub fn handle(
-    node: &Node,
+    node: &mut Node,
     address: PeerAddress,
     message_id: MessageId,
     proposal: ProtocolId,
-) -> Option<Message> {
+) -> Response {
+    // follow up on a negotiation we're tracking
+    let negotiations = node.negotiations_mut();
+    if let Some(response) = negotiations.choose(&address, message_id, &proposal) {
+        return Ok(response);
+    }
+
     // delegate the message
     let delegate = node.borrow_delegate();
-    delegate.handle_negotiated_protocol(address, message_id, proposal);
+    let handling = delegate.handle_negotiated_protocol(address, message_id, proposal);
+
+    node.drive(handling);
 
-    None
+    Ok(None)
 }
@@ -0,0 +1,31 @@
+use super::{MessageId, ProtocolId, Response};
+use crate::{Node, PeerAddress};
+use tracing::debug;
+
+/*
+
+    If we are receiving this message, a peer took the payload we sent along
+    with the protocol it chose, and the negotiation is over. Only the node
+    keeps track of what it sent; a delegate that negotiates by hand has
+    nothing to do with it.
+
+*/
+pub fn handle(
+    node: &mut Node,
+    address: PeerAddress,
+    message_id: MessageId,
+    proposal: ProtocolId,
+) -> Response {
+    let negotiations = node.negotiations_mut();
+    if negotiations
+        .acknowledge(&address, message_id, &proposal)
+        .is_none()
+    {
+        debug!(
+            message_id,
+            "acknowledgment for a negotiation we aren't tracking"
+        );
+    }
+
+    Ok(None)
+}
@@ -1,22 +1,33 @@
-use super::{MessageId, PageCount};
-use crate::{Message, Node, PeerAddress};
+use super::{MessageId, PageCount, Response};
+use crate::{Node, PeerAddress};
 
 /*
 
     If we are receiving this message, it's because we sent a negotiated message
-    and the receiver wasn't able to handle it. We need to notify the delegate,
-    who will determine if it will try again with new proposals.
+    and the receiver wasn't able to handle any of the proposals on that page.
+    If the node sent it for us, we follow up with the next page of proposals,
+    and only resolve the negotiation as failed once we've run out of pages.
+    Otherwise we need to notify the delegate, who will determine if it will
+    try again with new proposals.
 
 */
 pub fn handle(
-    node: &Node,
+    node: &mut Node,
     address: PeerAddress,
     message_id: MessageId,
     page_count: PageCount,
-) -> Option<Message> {
+) -> Response {
+    // move on to the next page of a negotiation we're tracking
+    let negotiations = node.negotiations_mut();
+    if let Some(response) = negotiations.fail(&address, message_id, page_count) {
+        return Ok(response);
+    }
+
     // delegate the message
     let delegate = node.borrow_delegate();
-    delegate.handle_negotiation_failure(address, message_id, page_count);
+    let handling = delegate.handle_negotiation_failure(address, message_id, page_count);
+
+    node.drive(handling);
 
-    None
+    Ok(None)
 }
@@ -0,0 +1,207 @@
+use super::{Message, MessageId, PageCount, PayloadMask, ProtocolId, ProtocolKey};
+use bytes::Bytes;
+use serde::{de, Deserialize, Deserializer, Serializer};
+use std::borrow::Cow;
+use std::fmt;
+
+/*
+
+    How payloads go over the wire.
+
+    `Message` itself sends payloads as byte strings, through the
+    `#[serde(with)]` functions below.
+
+    A format that decodes straight out of the frame hands payloads over as
+    slices of it, which serde can't pass down to those. So such formats
+    decode into `Encoded` instead, the same message field for field with
+    its payloads as `Slice`s, and the message is then built with payloads
+    sliced out of the frame itself rather than copied: the payload a
+    handler gets shares the buffer the transport received it into.
+
+*/
+
+#[derive(Deserialize)]
+#[serde(rename = "Message")]
+pub(crate) enum Encoded<'a, P> {
+    NegotiableMessage {
+        message_id: MessageId,
+        page_count: PageCount,
+        proposals: Cow<'a, [ProtocolId]>,
+        payload_mask: Cow<'a, PayloadMask>,
+        payload: P,
+    },
+    NegotiatedProtocolChoice {
+        message_id: MessageId,
+        proposal: Cow<'a, [u8]>,
+    },
+    NegotiationFailed {
+        message_id: MessageId,
+        page_count: PageCount,
+    },
+    ConnectionAccepted {
+        protocol: Cow<'a, [u8]>,
+        key: ProtocolKey,
+        payload: P,
+    },
+    ConnectionConfirmed {
+        protocol: Cow<'a, [u8]>,
+        key: ProtocolKey,
+        payload: P,
+    },
+    ConnectionClosed {
+        key: ProtocolKey,
+        payload: P,
+    },
+    ConnectionMessage {
+        key: ProtocolKey,
+        payload: P,
+    },
+    ConnectionRequested {
+        protocol: Cow<'a, [u8]>,
+        payload: P,
+    },
+    Ping,
+    Pong,
+    NegotiationAcknowledged {
+        message_id: MessageId,
+        proposal: Cow<'a, [u8]>,
+    },
+}
+
+impl<P> Encoded<'_, P> {
+    // the message this decoded into, with each payload made by `payload`
+    pub(crate) fn into_message(self, payload: impl Fn(P) -> Bytes) -> Message {
+        match self {
+            Encoded::NegotiableMessage {
+                message_id,
+                page_count,
+                proposals,
+                payload_mask,
+                payload: encoded,
+            } => Message::NegotiableMessage {
+                message_id,
+                page_count,
+                proposals: proposals.into_owned(),
+                payload_mask: payload_mask.into_owned(),
+                payload: payload(encoded),
+            },
+            Encoded::NegotiatedProtocolChoice {
+                message_id,
+                proposal,
+            } => Message::NegotiatedProtocolChoice {
+                message_id,
+                proposal: proposal.into_owned(),
+            },
+            Encoded::NegotiationFailed {
+                message_id,
+                page_count,
+            } => Message::NegotiationFailed {
+                message_id,
+                page_count,
+            },
+            Encoded::ConnectionAccepted {
+                protocol,
+                key,
+                payload: encoded,
+            } => Message::ConnectionAccepted {
+                protocol: protocol.into_owned(),
+                key,
+                payload: payload(encoded),
+            },
+            Encoded::ConnectionConfirmed {
+                protocol,
+                key,
+                payload: encoded,
+            } => Message::ConnectionConfirmed {
+                protocol: protocol.into_owned(),
+                key,
+                payload: payload(encoded),
+            },
+            Encoded::ConnectionClosed {
+                key,
+                payload: encoded,
+            } => Message::ConnectionClosed {
+                key,
+                payload: payload(encoded),
+            },
+            Encoded::ConnectionMessage {
+                key,
+                payload: encoded,
+            } => Message::ConnectionMessage {
+                key,
+                payload: payload(encoded),
+            },
+            Encoded::ConnectionRequested {
+                protocol,
+                payload: encoded,
+            } => Message::ConnectionRequested {
+                protocol: protocol.into_owned(),
+                payload: payload(encoded),
+            },
+            Encoded::Ping => Message::Ping,
+            Encoded::Pong => Message::Pong,
+            Encoded::NegotiationAcknowledged {
+                message_id,
+                proposal,
+            } => Message::NegotiationAcknowledged {
+                message_id,
+                proposal: proposal.into_owned(),
+            },
+        }
+    }
+}
+
+// a decoded payload, still pointing into the frame if the format let it
+pub(crate) enum Slice<'de> {
+    Borrowed(&'de [u8]),
+    Owned(Bytes),
+}
+impl Slice<'_> {
+    // `frame` has to be what the payload was decoded from
+    pub(crate) fn into_bytes(self, frame: &Bytes) -> Bytes {
+        match self {
+            Slice::Borrowed(bytes) => frame.slice_ref(bytes),
+            Slice::Owned(bytes) => bytes,
+        }
+    }
+}
+impl<'de> Deserialize<'de> for Slice<'de> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_byte_buf(PayloadVisitor)
+    }
+}
+
+pub fn serialize<S: Serializer>(payload: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bytes(payload)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+    match Slice::deserialize(deserializer)? {
+        Slice::Borrowed(bytes) => Ok(Bytes::copy_from_slice(bytes)),
+        Slice::Owned(bytes) => Ok(bytes),
+    }
+}
+
+// asked for a byte buf, not bytes: formats that can't borrow then hand
+// over the buffer they read into rather than a scratch slice we'd copy,
+// and ciborium only reads small byte strings as bytes
+struct PayloadVisitor;
+impl<'de> de::Visitor<'de> for PayloadVisitor {
+    type Value = Slice<'de>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a payload byte string")
+    }
+
+    fn visit_borrowed_bytes<E: de::Error>(self, bytes: &'de [u8]) -> Result<Self::Value, E> {
+        Ok(Slice::Borrowed(bytes))
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+        Ok(Slice::Owned(Bytes::copy_from_slice(bytes)))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(Slice::Owned(bytes.into()))
+    }
+}
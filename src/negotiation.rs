@@ -0,0 +1,367 @@
+use crate::message::{Message, MessageId, PageCount, Payload, PayloadMask, ProtocolId};
+use crate::transport::PeerAddress;
+use crate::RelayError;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+
+pub type Proposal = (ProtocolId, Option<Payload>);
+
+pub(crate) const DEFAULT_PAGE_SIZE: usize = 256;
+pub(crate) const DEFAULT_NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NegotiationOutcome {
+    Negotiated(ProtocolId),
+    Failed,
+}
+
+pub type NegotiationRx = oneshot::Receiver<NegotiationOutcome>;
+
+struct Negotiation {
+    proposals: Vec<Proposal>,
+    pages: Vec<Vec<usize>>,
+    page: PageCount,
+    inline: Vec<bool>,
+    outcome_tx: oneshot::Sender<NegotiationOutcome>,
+    // when we last sent the peer something to answer
+    sent: Instant,
+}
+impl Negotiation {
+    fn page_message(&mut self, message_id: MessageId) -> Message {
+        self.sent = Instant::now();
+        let indices = &self.pages[self.page as usize];
+
+        // only one payload fits in a message, so carry the first one this
+        // page has along with every other proposal on the page that shares it
+        let payload = indices
+            .iter()
+            .find_map(|index| self.proposals[*index].1.clone());
+        let mut page_inline = Vec::with_capacity(indices.len());
+        for index in indices {
+            let carried = &self.proposals[*index].1;
+            let inline = carried.is_some() && *carried == payload;
+            self.inline[*index] = inline;
+            page_inline.push(inline);
+        }
+
+        Message::NegotiableMessage {
+            message_id,
+            page_count: self.page,
+            proposals: indices
+                .iter()
+                .map(|index| self.proposals[*index].0.clone())
+                .collect(),
+            payload_mask: page_inline.into_iter().collect(),
+            payload: payload.unwrap_or_default(),
+        }
+    }
+
+    fn resolve(self, outcome: NegotiationOutcome) {
+        // the caller may have stopped waiting, which is fine
+        let _ = self.outcome_tx.send(outcome);
+    }
+}
+
+/*
+
+    Negotiations the peer never answers fail once they've waited `timeout`
+    for the peer's answer to what we last sent, and every negotiation with
+    a peer fails as soon as the transport loses it.
+
+*/
+pub(crate) struct Negotiations {
+    page_size: usize,
+    timeout: Duration,
+    ticker: Interval,
+    last_id: MessageId,
+    pending: HashMap<(PeerAddress, MessageId), Negotiation>,
+}
+
+impl Negotiations {
+    pub(crate) fn new(page_size: usize, timeout: Duration) -> Negotiations {
+        // look for negotiations past their timeout twice per timeout
+        let period = (timeout / 2).max(Duration::from_millis(1));
+        let mut ticker = time::interval_at(Instant::now() + period, period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Negotiations {
+            page_size,
+            timeout,
+            ticker,
+            last_id: 0,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn start(
+        &mut self,
+        address: PeerAddress,
+        proposals: Vec<Proposal>,
+        outcome_tx: oneshot::Sender<NegotiationOutcome>,
+    ) -> Result<Option<(MessageId, Message)>, RelayError> {
+        if proposals.is_empty() {
+            let _ = outcome_tx.send(NegotiationOutcome::Failed);
+            return Ok(None);
+        }
+
+        let message_id = self.next_id(&address)?;
+        let mut negotiation = Negotiation {
+            pages: paginate(&proposals, self.page_size),
+            page: 0,
+            inline: vec![false; proposals.len()],
+            proposals,
+            outcome_tx,
+            sent: Instant::now(),
+        };
+        let message = negotiation.page_message(message_id);
+        self.pending.insert((address, message_id), negotiation);
+
+        Ok(Some((message_id, message)))
+    }
+
+    // forget about a negotiation without resolving it
+    pub(crate) fn cancel(&mut self, address: &PeerAddress, message_id: MessageId) {
+        self.pending.remove(&(address.clone(), message_id));
+    }
+
+    // returns None if we aren't tracking this negotiation
+    pub(crate) fn choose(
+        &mut self,
+        address: &PeerAddress,
+        message_id: MessageId,
+        protocol_id: &ProtocolId,
+    ) -> Option<Option<Message>> {
+        let key = (address.clone(), message_id);
+        let mut negotiation = self.pending.remove(&key)?;
+
+        let index = match negotiation
+            .proposals
+            .iter()
+            .position(|(id, _)| id == protocol_id)
+        {
+            Some(index) => index,
+            None => {
+                // peer chose something we never proposed
+                negotiation.resolve(NegotiationOutcome::Failed);
+                return Some(None);
+            }
+        };
+
+        // peer already has what it needs, we're done; peers that predate
+        // acknowledgments answer a payload they took with their choice
+        let payload = match &negotiation.proposals[index].1 {
+            Some(payload) if !negotiation.inline[index] => payload.clone(),
+            _ => {
+                negotiation.resolve(NegotiationOutcome::Negotiated(protocol_id.clone()));
+                return Some(None);
+            }
+        };
+
+        // send the payload for the chosen protocol, and wait for the peer to
+        // acknowledge it with another choice
+        negotiation.inline[index] = true;
+        negotiation.sent = Instant::now();
+        let page_count = negotiation.page;
+        self.pending.insert(key, negotiation);
+        Some(Some(Message::NegotiableMessage {
+            message_id,
+            page_count,
+            proposals: vec![protocol_id.clone()],
+            payload_mask: PayloadMask::from_iter([true]),
+            payload,
+        }))
+    }
+
+    // the peer took the payload for `protocol_id`; returns None if we aren't
+    // tracking this negotiation
+    pub(crate) fn acknowledge(
+        &mut self,
+        address: &PeerAddress,
+        message_id: MessageId,
+        protocol_id: &ProtocolId,
+    ) -> Option<()> {
+        let negotiation = self.pending.remove(&(address.clone(), message_id))?;
+        // the peer may have taken something we never proposed
+        let proposed = negotiation
+            .proposals
+            .iter()
+            .any(|(id, _)| id == protocol_id);
+        if proposed {
+            negotiation.resolve(NegotiationOutcome::Negotiated(protocol_id.clone()));
+        } else {
+            negotiation.resolve(NegotiationOutcome::Failed);
+        }
+        Some(())
+    }
+
+    // returns None if we aren't tracking this negotiation
+    pub(crate) fn fail(
+        &mut self,
+        address: &PeerAddress,
+        message_id: MessageId,
+        page_count: PageCount,
+    ) -> Option<Option<Message>> {
+        let key = (address.clone(), message_id);
+        let mut negotiation = self.pending.remove(&key)?;
+
+        // a stale failure for a page we've already moved past
+        if page_count != negotiation.page {
+            self.pending.insert(key, negotiation);
+            return Some(None);
+        }
+
+        // that was the last page, the negotiation failed
+        let next_page = page_count as usize + 1;
+        if next_page >= negotiation.pages.len() {
+            negotiation.resolve(NegotiationOutcome::Failed);
+            return Some(None);
+        }
+
+        // try the next page of proposals
+        negotiation.page = next_page as PageCount;
+        let message = negotiation.page_message(message_id);
+        self.pending.insert(key, negotiation);
+        Some(Some(message))
+    }
+
+    // fail every negotiation with a peer the transport lost
+    pub(crate) fn forget(&mut self, address: &PeerAddress) {
+        let lost: Vec<_> = self
+            .pending
+            .keys()
+            .filter(|(peer, _)| peer == address)
+            .cloned()
+            .collect();
+        for key in lost {
+            if let Some(negotiation) = self.pending.remove(&key) {
+                negotiation.resolve(NegotiationOutcome::Failed);
+            }
+        }
+    }
+
+    // resolves whenever it's time to look for negotiations past their timeout
+    pub(crate) async fn tick(&mut self) {
+        self.ticker.tick().await;
+    }
+
+    // fail the negotiations the peer hasn't answered in time
+    pub(crate) fn expire(&mut self) {
+        let timeout = self.timeout;
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, negotiation)| negotiation.sent.elapsed() >= timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            if let Some(negotiation) = self.pending.remove(&key) {
+                negotiation.resolve(NegotiationOutcome::Failed);
+            }
+        }
+    }
+
+    fn next_id(&mut self, address: &PeerAddress) -> Result<MessageId, RelayError> {
+        // skip over ids still in flight with this peer
+        for _ in 0..=MessageId::MAX {
+            self.last_id = self.last_id.wrapping_add(1);
+            if !self.pending.contains_key(&(address.clone(), self.last_id)) {
+                return Ok(self.last_id);
+            }
+        }
+        Err(RelayError::MessageIdsExhausted(address.clone()))
+    }
+}
+
+// split proposals into pages that stay within the size budget, every page
+// holds at least one proposal no matter how large it is
+fn paginate(proposals: &[Proposal], page_size: usize) -> Vec<Vec<usize>> {
+    let max_pages = PageCount::MAX as usize + 1;
+    let mut pages: Vec<Vec<usize>> = Vec::new();
+    let mut page = Vec::new();
+    let mut size = 0;
+    let mut has_payload = false;
+    for (index, (id, payload)) in proposals.iter().enumerate() {
+        let mut cost = id.len();
+        if let (false, Some(payload)) = (has_payload, payload) {
+            cost += payload.len();
+        }
+
+        if !page.is_empty() && size + cost > page_size && pages.len() + 1 < max_pages {
+            pages.push(std::mem::take(&mut page));
+            size = 0;
+            has_payload = false;
+            cost = id.len() + payload.as_ref().map_or(0, |p| p.len());
+        }
+
+        has_payload |= payload.is_some();
+        size += cost;
+        page.push(index);
+    }
+    pages.push(page);
+    pages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proposal(id: &str, payload: Option<usize>) -> Proposal {
+        (
+            id.as_bytes().to_vec(),
+            payload.map(|len| Payload::from(vec![0; len])),
+        )
+    }
+
+    #[test]
+    fn pages_stay_within_the_page_size() {
+        let proposals: Vec<Proposal> = (0..5)
+            .map(|index| proposal(&format!("protocol/{}", index), None))
+            .collect();
+        assert_eq!(
+            paginate(&proposals, 25),
+            vec![vec![0, 1], vec![2, 3], vec![4]]
+        );
+        assert_eq!(paginate(&proposals, 1000), vec![vec![0, 1, 2, 3, 4]]);
+    }
+
+    #[test]
+    fn proposals_larger_than_a_page_get_one_to_themselves() {
+        let proposals = [
+            proposal("a", None),
+            proposal("b", Some(100)),
+            proposal("c", None),
+        ];
+        assert_eq!(paginate(&proposals, 20), vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn only_the_payload_a_page_carries_counts_against_it() {
+        // the second payload goes out later, only its id takes up room
+        let proposals = [
+            proposal("aa", Some(20)),
+            proposal("bb", Some(20)),
+            proposal("cc", None),
+        ];
+        assert_eq!(paginate(&proposals, 30), vec![vec![0, 1, 2]]);
+
+        // but it's carried in full when it starts a page of its own
+        let proposals = [
+            proposal("aa", None),
+            proposal("bb", Some(20)),
+            proposal("cc", None),
+        ];
+        assert_eq!(paginate(&proposals, 10), vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn the_last_page_takes_whatever_doesnt_fit_in_a_page_count() {
+        let proposals: Vec<Proposal> = (0..300)
+            .map(|index| proposal(&index.to_string(), None))
+            .collect();
+        let pages = paginate(&proposals, 1);
+        assert_eq!(pages.len(), PageCount::MAX as usize + 1);
+        assert_eq!(pages[0], vec![0]);
+        assert_eq!(pages.last().unwrap(), &(255..300).collect::<Vec<_>>());
+    }
+}
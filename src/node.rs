@@ -1,11 +1,43 @@
-use crate::message::{self, Message, MessageId, PageCount, ProtocolId, ProtocolKey};
-use crate::protocol::Protocol;
-use crate::transport::{router::Router, Message as TransportMessage, TransportRx};
-use crate::{PeerAddress, ProtocolHandler};
-use futures::StreamExt;
-use std::collections::HashMap;
-
-pub trait Delegate {
+use crate::codec::Codec;
+use crate::heartbeat::{self, Heartbeats};
+use crate::message::{
+    self, Message, MessageId, PageCount, Payload, ProtocolId, ProtocolKey, Response,
+};
+use crate::negotiation::{NegotiationOutcome, NegotiationRx, Negotiations, Proposal};
+use crate::noise::{self, PublicKey, Sessions};
+use crate::peer::PeerId;
+use crate::protocol::{registry::Registry, AsyncHandler, Protocol, SyncHandler};
+use crate::transport::{
+    router::Router, Delivery, Message as TransportMessage, TransportEvent, TransportProtocol,
+    TransportRx,
+};
+use crate::wire::{self, Frame, Versions, WireVersion};
+use crate::{PeerAddress, ProtocolHandler, RelayError};
+use bytes::Bytes;
+use futures::{
+    future::{self, BoxFuture, FutureExt},
+    stream::FuturesUnordered,
+    task::noop_waker_ref,
+    StreamExt,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    path::Path,
+    task::{Context, Poll},
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, debug_span, error, warn};
+
+pub mod builder;
+pub mod handle;
+
+use builder::Config;
+pub use builder::NodeBuilder;
+pub use handle::NodeHandle;
+use handle::{Command, CommandRx, CommandTx, ResultTx};
+
+pub trait Delegate: Send {
     fn handle_negotiated_protocol(
         &self,
         address: PeerAddress,
@@ -20,96 +52,951 @@ pub trait Delegate {
     );
 }
 
+// same as `Delegate`, but the node drives the returned futures
+pub trait AsyncDelegate: Send {
+    fn handle_negotiated_protocol(
+        &self,
+        address: PeerAddress,
+        message_id: MessageId,
+        protocol_id: ProtocolId,
+    ) -> BoxFuture<'static, ()>;
+    fn handle_negotiation_failure(
+        &self,
+        address: PeerAddress,
+        message_id: MessageId,
+        page_count: PageCount,
+    ) -> BoxFuture<'static, ()>;
+}
+
+pub(crate) struct SyncDelegate(pub(crate) Box<dyn Delegate>);
+impl AsyncDelegate for SyncDelegate {
+    fn handle_negotiated_protocol(
+        &self,
+        address: PeerAddress,
+        message_id: MessageId,
+        protocol_id: ProtocolId,
+    ) -> BoxFuture<'static, ()> {
+        self.0
+            .handle_negotiated_protocol(address, message_id, protocol_id);
+        future::ready(()).boxed()
+    }
+    fn handle_negotiation_failure(
+        &self,
+        address: PeerAddress,
+        message_id: MessageId,
+        page_count: PageCount,
+    ) -> BoxFuture<'static, ()> {
+        self.0
+            .handle_negotiation_failure(address, message_id, page_count);
+        future::ready(()).boxed()
+    }
+}
+
+// work to apply to the node once a handler or delegate future resolves
+type Deferred = Box<dyn FnOnce(&mut Node) + Send>;
+
+// a send through a handle that waits for room in the peer's transport
+struct Waiting {
+    message: Message,
+    delivery: Delivery,
+    result_tx: ResultTx,
+}
+
 pub struct Node {
     router: Router,
-    message_stream: TransportRx,
-    last_key: u8,
-    delegate: Box<dyn Delegate>,
-    protocols_by_id: HashMap<ProtocolId, Protocol>,
-    ids_by_key: HashMap<ProtocolKey, ProtocolId>,
+    event_stream: TransportRx,
+    command_tx: CommandTx,
+    command_rx: CommandRx,
+    deferred: FuturesUnordered<BoxFuture<'static, Deferred>>,
+    // messages from peers with a verification still pending, handled in
+    // order once it resolves
+    held: HashMap<PeerId, VecDeque<(PeerAddress, Message)>>,
+    hold_capacity: usize,
+    // sends waiting for room, one at a time and in order for every peer; a
+    // peer is in here while its first send is on its way through `sending`
+    waiting: HashMap<PeerAddress, VecDeque<Waiting>>,
+    sending: FuturesUnordered<BoxFuture<'static, PeerAddress>>,
+    delegate: Box<dyn AsyncDelegate>,
+    negotiations: Negotiations,
+    registry: Registry,
+    delivery: Delivery,
+    peer_delivery: HashMap<PeerAddress, Delivery>,
+    // only when the node has a keypair
+    sessions: Option<Sessions>,
+    // where to reach every peer some protocol is connected to
+    peer_addresses: HashMap<PeerId, PeerAddress>,
+    heartbeats: Option<Heartbeats>,
+    versions: Versions,
+    codec: Codec,
 }
 
 impl Node {
-    pub fn new(delegate: Box<dyn Delegate>) -> Node {
-        let port = 27850;
-        let buffer_size = 512;
-        let (router, message_stream) = Router::new(port, buffer_size);
+    pub async fn new(delegate: Box<dyn Delegate>) -> Result<Node, RelayError> {
+        NodeBuilder::new(delegate).build().await
+    }
+
+    pub fn builder(delegate: Box<dyn Delegate>) -> NodeBuilder {
+        NodeBuilder::new(delegate)
+    }
 
-        Node {
+    pub(crate) async fn with_config(
+        delegate: Box<dyn AsyncDelegate>,
+        config: Config,
+    ) -> Result<Node, RelayError> {
+        let hold_capacity = config.transport.queues.peer_capacity;
+        let (command_tx, command_rx) = mpsc::channel(config.transport.queues.capacity);
+        let (router, event_stream) = Router::new(config.transport, config.transports).await?;
+
+        Ok(Node {
             router,
-            message_stream,
-            last_key: 0,
+            event_stream,
+            command_tx,
+            command_rx,
+            deferred: FuturesUnordered::new(),
+            held: HashMap::new(),
+            hold_capacity,
+            waiting: HashMap::new(),
+            sending: FuturesUnordered::new(),
             delegate,
-            protocols_by_id: HashMap::new(),
-            ids_by_key: HashMap::new(),
+            negotiations: Negotiations::new(config.page_size, config.negotiation_timeout),
+            registry: Registry::default(),
+            delivery: config.delivery,
+            peer_delivery: HashMap::new(),
+            sessions: config.keypair.map(Sessions::new),
+            peer_addresses: HashMap::new(),
+            heartbeats: config.heartbeat.map(Heartbeats::new),
+            versions: Versions::default(),
+            codec: config.codec,
+        })
+    }
+
+    pub fn handle(&self) -> NodeHandle {
+        NodeHandle::new(self.command_tx.clone())
+    }
+
+    // where the tcp listener is bound, unless the node was built without it
+    pub fn tcp_address(&self) -> Option<SocketAddr> {
+        self.router.tcp_address()
+    }
+
+    // where the udp socket is bound, unless the node was built without it
+    pub fn udp_address(&self) -> Option<SocketAddr> {
+        self.router.udp_address()
+    }
+
+    pub fn unix_stream_path(&self) -> Option<&Path> {
+        self.router.unix_stream_path()
+    }
+
+    pub fn unix_datagram_path(&self) -> Option<&Path> {
+        self.router.unix_datagram_path()
+    }
+
+    pub fn public_key(&self) -> Option<&PublicKey> {
+        self.sessions.as_ref().map(Sessions::public_key)
+    }
+
+    // the static key `address` proved it holds, once the handshake with it
+    // is done
+    pub fn remote_key(&self, address: &PeerAddress) -> Option<&PublicKey> {
+        self.sessions.as_ref()?.remote_key(address)
+    }
+
+    // who is at `address`; with a keypair that's only known once the
+    // handshake with it is done
+    pub fn peer_id(&self, address: &PeerAddress) -> Option<PeerId> {
+        match &self.sessions {
+            None => Some(PeerId::from_address(address)),
+            Some(sessions) => sessions.remote_key(address).map(PeerId::from_public_key),
         }
     }
 
-    pub fn register_protocol(&mut self, id: ProtocolId, handler: Box<dyn ProtocolHandler>) {
-        // remove registered handler
-        //   if it existed, extract and reuse existing key/peer_keys
-        //   if not, create new key/peer_keys
-        let (key, peer_keys) = match self.protocols_by_id.remove(&id) {
-            Some(protocol) => (protocol.key, protocol.peer_keys.clone()),
-            None => (self.get_next_key(), HashMap::new()),
+    // where we last heard from `peer`, as long as a protocol is connected to it
+    pub fn peer_address(&self, peer: &PeerId) -> Option<&PeerAddress> {
+        self.peer_addresses.get(peer)
+    }
+
+    // the wire version and codec frames to `address` are sent with
+    pub fn wire_format(&self, address: &PeerAddress) -> (WireVersion, Codec) {
+        self.versions.format(address, self.codec)
+    }
+
+    pub fn register_protocol(
+        &mut self,
+        id: ProtocolId,
+        handler: Box<dyn ProtocolHandler>,
+    ) -> Result<ProtocolKey, RelayError> {
+        self.registry.register(id, Box::new(SyncHandler(handler)))
+    }
+
+    pub fn register_async_protocol(
+        &mut self,
+        id: ProtocolId,
+        handler: Box<dyn AsyncHandler>,
+    ) -> Result<ProtocolKey, RelayError> {
+        self.registry.register(id, handler)
+    }
+
+    pub fn unregister_protocol(&mut self, id: &ProtocolId) -> Result<(), RelayError> {
+        let protocol = match self.registry.unregister(id) {
+            None => return Err(RelayError::UnknownProtocolId(id.clone())),
+            Some(protocol) => protocol,
+        };
+
+        // let every connected peer know the connection is gone
+        let mut result = Ok(());
+        for (peer, peer_key) in protocol.peer_keys {
+            if let Some(address) = self.peer_addresses.get(&peer).cloned() {
+                let message = Message::ConnectionClosed {
+                    key: peer_key,
+                    payload: Payload::new(),
+                };
+                result = result.and(self.send(address, message));
+            }
+            self.forget_peer(&peer);
+        }
+
+        result
+    }
+
+    pub fn protocols(&self) -> Vec<ProtocolId> {
+        self.registry.ids().cloned().collect()
+    }
+
+    // runs the node on its own task until it is shut down through the
+    // handle; if the transports stop on their own the error is logged, run
+    // `listen` yourself to handle it instead
+    pub fn spawn(mut self) -> NodeHandle {
+        let handle = self.handle();
+        tokio::spawn(async move {
+            if let Err(err) = self.listen().await {
+                error!(error = %err, "node stopped");
+            }
+        });
+        handle
+    }
+
+    // returns once the node has been shut down through a handle, or with an
+    // error if the transports stop on their own
+    pub async fn listen(&mut self) -> Result<(), RelayError> {
+        loop {
+            tokio::select! {
+                // finish work for messages we've already received first
+                biased;
+                Some(apply) = self.deferred.next(), if !self.deferred.is_empty() => apply(self),
+                Some(address) = self.sending.next(), if !self.sending.is_empty() => self.send_next(address),
+                Some(command) = self.command_rx.recv() => match command {
+                    Command::Shutdown { done_tx } => {
+                        let result = self.shutdown().await;
+                        let _ = done_tx.send(());
+                        return result;
+                    }
+                    command => self.execute(command),
+                },
+                // ahead of events, a busy peer mustn't keep us from noticing a dead one
+                () = heartbeat::tick(&mut self.heartbeats) => self.heartbeat(),
+                () = self.negotiations.tick() => self.negotiations.expire(),
+                () = noise::tick(&mut self.sessions) => self.expire_handshakes(),
+                event = self.event_stream.next() => match event {
+                    Some(TransportEvent::Message(transport_message)) => self.receive(transport_message),
+                    Some(TransportEvent::Connected(address)) => debug!(peer = ?address, "connected"),
+                    Some(TransportEvent::Disconnected(address)) => self.disconnected(address),
+                    None => return Err(RelayError::TransportClosed),
+                },
+            }
+        }
+    }
+
+    // finish the sends still waiting on a transport, close every protocol
+    // connection we have, then stop the transports once those messages are
+    // on their way
+    pub async fn shutdown(&mut self) -> Result<(), RelayError> {
+        while let Some(address) = self.sending.next().await {
+            self.send_next(address);
+        }
+
+        let mut closed = Vec::new();
+        for id in self.protocols() {
+            if let Some(protocol) = self.get_protocol_mut(&id) {
+                closed.extend(protocol.peer_keys.drain());
+            }
+        }
+        let mut result = Ok(());
+        for (peer, key) in closed {
+            let address = match self.peer_addresses.get(&peer).cloned() {
+                Some(address) => address,
+                None => continue,
+            };
+            let payload = Payload::new();
+            let delivery = self.delivery(&address);
+            let message = Message::ConnectionClosed { key, payload };
+            let sent = self.send_when_ready(address, message, delivery).await;
+            result = result.and(sent);
+        }
+        self.peer_addresses.clear();
+
+        self.router.shutdown().await;
+        result
+    }
+
+    fn execute(&mut self, command: Command) {
+        match command {
+            Command::Shutdown { .. } => {} // handled by the listen loop
+            Command::Connect {
+                address,
+                protocol_id,
+                payload,
+                result_tx,
+            } => {
+                let result = self.connect(address, protocol_id, payload);
+                let _ = result_tx.send(result);
+            }
+            Command::Send {
+                address,
+                protocol_id,
+                payload,
+                delivery,
+                wait,
+                result_tx,
+            } => {
+                let delivery = delivery.unwrap_or_else(|| self.delivery(&address));
+                if !wait {
+                    // going ahead of sends that are waiting would reorder them
+                    let result = if self.waiting.contains_key(&address) {
+                        Err(RelayError::QueueFull(address))
+                    } else {
+                        self.send_message_with(address, &protocol_id, payload, delivery)
+                    };
+                    let _ = result_tx.send(result);
+                    return;
+                }
+                match self.peer_key(&address, &protocol_id) {
+                    Ok(key) => {
+                        let message = Message::ConnectionMessage { key, payload };
+                        let waiting = Waiting {
+                            message,
+                            delivery,
+                            result_tx,
+                        };
+                        self.send_in_order(address, waiting);
+                    }
+                    Err(err) => {
+                        let _ = result_tx.send(Err(err));
+                    }
+                }
+            }
+            Command::SetDelivery { address, delivery } => self.set_delivery(address, delivery),
+            Command::Close {
+                address,
+                protocol_id,
+                payload,
+                result_tx,
+            } => {
+                let result = self.close(address, &protocol_id, payload);
+                let _ = result_tx.send(result);
+            }
+            Command::Negotiate {
+                address,
+                proposals,
+                result_tx,
+                outcome_tx,
+            } => {
+                let result = self.negotiate(address, proposals, outcome_tx);
+                let _ = result_tx.send(result);
+            }
+        }
+    }
+
+    // unless the peer is still reachable somewhere else, forget its keys and
+    // let every protocol it was connected to know
+    fn disconnected(&mut self, address: PeerAddress) {
+        debug!(peer = ?address, "disconnected");
+        let peer = self.peer_id(&address);
+        if let Some(sessions) = &mut self.sessions {
+            sessions.remove(&address);
+        }
+        self.versions.remove(&address);
+        self.negotiations.forget(&address);
+        let peer = match peer {
+            Some(peer) => peer,
+            None => return,
+        };
+        if self.peer_addresses.get(&peer) != Some(&address) {
+            return; // not the connection we've been using
+        }
+
+        let elsewhere = self
+            .sessions
+            .iter()
+            .flat_map(Sessions::established)
+            .find_map(|(other, remote_key)| {
+                (PeerId::from_public_key(remote_key) == peer).then(|| other.clone())
+            });
+        if let Some(other) = elsewhere {
+            debug!(?peer, address = ?other, "still connected");
+            self.peer_addresses.insert(peer, other);
+            return;
+        }
+        self.lose(peer, address);
+    }
+
+    // ping the peers that have gone quiet, and give up on the ones that
+    // haven't answered
+    fn heartbeat(&mut self) {
+        let silent = match &mut self.heartbeats {
+            Some(heartbeats) => heartbeats.check(&self.peer_addresses),
+            None => return,
         };
+        for address in silent.ping {
+            if let Err(err) = self.send(address.clone(), Message::Ping) {
+                debug!(peer = ?address, error = %err, "couldn't send ping");
+            }
+        }
+        for peer in silent.expired {
+            self.expire(peer);
+        }
+    }
 
-        // construct a new protocol and insert it into the table
-        let protocol = Protocol {
-            handler,
-            key,
-            peer_keys,
+    // close every protocol connection to a peer that stopped responding, and
+    // drop the transport connection
+    fn expire(&mut self, peer: PeerId) {
+        let address = match self.peer_addresses.get(&peer).cloned() {
+            Some(address) => address,
+            None => return,
+        };
+        warn!(?peer, ?address, "peer stopped responding");
+        self.lose(peer, address.clone());
+        self.negotiations.forget(&address);
+        self.versions.remove(&address);
+        if let Some(sessions) = &mut self.sessions {
+            sessions.remove(&address);
+        }
+        self.router.disconnect(&address);
+    }
+
+    // forget the peer's keys, and let every protocol it was connected to know
+    fn lose(&mut self, peer: PeerId, address: PeerAddress) {
+        self.peer_addresses.remove(&peer);
+        if let Some(heartbeats) = &mut self.heartbeats {
+            heartbeats.forget(&peer);
+        }
+        for id in self.protocols() {
+            let protocol = match self.registry.get_mut(&id) {
+                Some(protocol) => protocol,
+                None => continue,
+            };
+            if protocol.peer_keys.remove(&peer).is_none() {
+                continue;
+            }
+            debug!(?peer, ?address, protocol_id = ?id, "connection lost");
+            let lost = protocol
+                .handler
+                .connection_lost(peer.clone(), address.clone());
+            self.drive(lost);
+        }
+    }
+
+    fn expire_handshakes(&mut self) {
+        if let Some(sessions) = &mut self.sessions {
+            sessions.expire();
+        }
+    }
+
+    fn receive(&mut self, transport_message: TransportMessage) {
+        let TransportMessage { address, payload } = transport_message;
+        let handshake = self.delivery(&address);
+        let opened = match &mut self.sessions {
+            None => return self.relay(address, payload),
+            Some(sessions) => sessions.open(&address, payload, handshake),
+        };
+        let opened = match opened {
+            Ok(opened) => opened,
+            Err(err) => {
+                warn!(peer = ?address, error = %err, "couldn't open frame");
+                return;
+            }
         };
-        self.protocols_by_id.insert(id, protocol);
+
+        for (payload, delivery) in opened.replies {
+            let message = TransportMessage {
+                address: address.clone(),
+                payload,
+            };
+            if let Err(err) = self.router.try_send(message, delivery) {
+                warn!(peer = ?address, error = %err, "couldn't send handshake frame");
+            }
+        }
+        if let Some(remote_key) = opened.secured {
+            debug!(peer = ?address, ?remote_key, "secured");
+            self.secured(address.clone(), remote_key);
+        }
+        for payload in opened.frames {
+            self.relay(address.clone(), payload);
+        }
+    }
+
+    fn secured(&mut self, address: PeerAddress, remote_key: PublicKey) {
+        let peer = PeerId::from_public_key(&remote_key);
+        // a peer we know coming back from somewhere else
+        if let Some(current) = self.peer_addresses.get_mut(&peer) {
+            *current = address.clone();
+        }
+        for id in self.protocols() {
+            if let Some(protocol) = self.registry.get(&id) {
+                let secured =
+                    protocol
+                        .handler
+                        .secured(peer.clone(), address.clone(), remote_key.clone());
+                self.drive(secured);
+            }
+        }
     }
 
-    pub async fn listen(&mut self) {
-        while let Some(transport_message) = self.message_stream.next().await {
-            let TransportMessage { address, payload } = transport_message;
-            println!("RELAY: {:?} Received {} bytes", address, payload.len());
-            let relay_message = match payload.try_into() {
-                Ok(msg) => msg,
-                Err(err) => {
-                    println!("couldn't deserialize relay message: {}", err);
-                    continue;
+    fn relay(&mut self, address: PeerAddress, payload: Bytes) {
+        let span = debug_span!("relay", peer = ?address, bytes = payload.len());
+        let _entered = span.enter();
+
+        let relay_message = match wire::decode(payload) {
+            Ok(Frame::Message(message, codec)) => {
+                self.versions.heard(&address, codec);
+                message
+            }
+            Ok(Frame::Versions { min, max, reply }) => {
+                return self.negotiate_version(address, min, max, reply)
+            }
+            Err(RelayError::UnsupportedVersion(version)) => {
+                // tell the peer what we do speak so it can fall back
+                warn!(version, "unsupported wire version");
+                let frame = match self.versions.reannounce(&address) {
+                    Some(frame) => frame,
+                    None => return debug!("answered too many unsupported versions lately"),
+                };
+                let delivery = self.delivery(&address);
+                if let Err(err) = self.send_frame(address, frame, delivery) {
+                    debug!(error = %err, "couldn't send versions");
+                }
+                return;
+            }
+            Err(err) => {
+                warn!(error = %err, "couldn't deserialize relay message");
+                return;
+            }
+        };
+
+        let peer = match self.peer_id(&address) {
+            Some(peer) => peer,
+            None => return warn!("dropping relay message from unknown peer"),
+        };
+        if let Some(current) = self.peer_addresses.get_mut(&peer) {
+            *current = address.clone();
+        }
+        if let Some(heartbeats) = &mut self.heartbeats {
+            heartbeats.heard(&peer);
+        }
+
+        debug!(message = relay_message.kind(), "received");
+        let keep_alive = matches!(relay_message, Message::Ping | Message::Pong);
+        match self.held.get_mut(&peer) {
+            Some(held) if !keep_alive => {
+                if held.len() >= self.hold_capacity {
+                    return warn!("too many messages waiting on a verification, dropping");
                 }
+                held.push_back((address, relay_message));
+            }
+            _ => self.dispatch(peer, address, relay_message),
+        }
+    }
+
+    fn dispatch(&mut self, peer: PeerId, address: PeerAddress, relay_message: Message) {
+        if let Err(err) = message::handle(self, peer, address, relay_message) {
+            debug!(error = %err, "couldn't handle relay message");
+        }
+    }
+
+    // handle what `peer` sent while a verification was pending, until one of
+    // those messages starts another verification
+    fn release(&mut self, peer: PeerId) {
+        let mut held = match self.held.remove(&peer) {
+            Some(held) => held,
+            None => return,
+        };
+        while let Some((address, relay_message)) = held.pop_front() {
+            self.dispatch(peer.clone(), address, relay_message);
+            if let Some(holding) = self.held.get_mut(&peer) {
+                holding.extend(held);
+                return;
+            }
+        }
+    }
+
+    fn negotiate_version(
+        &mut self,
+        address: PeerAddress,
+        min: WireVersion,
+        max: WireVersion,
+        reply: bool,
+    ) {
+        let (version, answer) = self.versions.negotiate(&address, min, max, reply);
+        match version {
+            Some(version) => debug!(version, "negotiated wire version"),
+            None => warn!(min, max, "no wire version in common"),
+        }
+        if let Some(frame) = answer {
+            let delivery = self.delivery(&address);
+            if let Err(err) = self.send_frame(address, frame, delivery) {
+                debug!(error = %err, "couldn't send versions");
+            }
+        }
+    }
+
+    pub fn connect(
+        &mut self,
+        address: PeerAddress,
+        protocol_id: ProtocolId,
+        payload: Payload,
+    ) -> Result<(), RelayError> {
+        // we need the protocol too, to handle the peer accepting
+        if self.get_protocol(&protocol_id).is_none() {
+            return Err(RelayError::UnknownProtocolId(protocol_id));
+        }
+
+        let message = Message::ConnectionRequested {
+            protocol: protocol_id,
+            payload,
+        };
+        self.send(address, message)
+    }
+
+    pub fn send_negotiable(
+        &mut self,
+        address: PeerAddress,
+        proposals: Vec<Proposal>,
+    ) -> Result<NegotiationRx, RelayError> {
+        let (outcome_tx, outcome_rx) = oneshot::channel();
+        self.negotiate(address, proposals, outcome_tx)?;
+        Ok(outcome_rx)
+    }
+
+    fn negotiate(
+        &mut self,
+        address: PeerAddress,
+        proposals: Vec<Proposal>,
+        outcome_tx: oneshot::Sender<NegotiationOutcome>,
+    ) -> Result<(), RelayError> {
+        let negotiations = &mut self.negotiations;
+        let (message_id, message) =
+            match negotiations.start(address.clone(), proposals, outcome_tx)? {
+                None => return Ok(()), // nothing to propose
+                Some(started) => started,
             };
 
-            message::handle(self, address, relay_message);
+        // nobody will answer a proposal that never went out
+        if let Err(err) = self.send(address.clone(), message) {
+            self.negotiations.cancel(&address, message_id);
+            return Err(err);
         }
+        Ok(())
+    }
+
+    pub fn send_message(
+        &mut self,
+        address: PeerAddress,
+        protocol_id: &ProtocolId,
+        payload: Payload,
+    ) -> Result<(), RelayError> {
+        let delivery = self.delivery(&address);
+        self.send_message_with(address, protocol_id, payload, delivery)
+    }
+
+    // same as `send_message`, overriding the peer's delivery for this message
+    pub fn send_message_with(
+        &mut self,
+        address: PeerAddress,
+        protocol_id: &ProtocolId,
+        payload: Payload,
+        delivery: Delivery,
+    ) -> Result<(), RelayError> {
+        let key = self.peer_key(&address, protocol_id)?;
+        self.send_with(
+            address,
+            Message::ConnectionMessage { key, payload },
+            delivery,
+        )
     }
 
-    pub fn send(&mut self, address: PeerAddress, message: Message) {
-        let payload = match message.try_into() {
-            Ok(bytes) => bytes,
-            Err(_) => todo!(),
+    pub fn close(
+        &mut self,
+        address: PeerAddress,
+        protocol_id: &ProtocolId,
+        payload: Payload,
+    ) -> Result<(), RelayError> {
+        let peer = match self.peer_id(&address) {
+            None => return Err(RelayError::NotConnected(address)),
+            Some(peer) => peer,
+        };
+        let key = match self.get_protocol_mut(protocol_id) {
+            None => return Err(RelayError::UnknownProtocolId(protocol_id.clone())),
+            Some(p) => match p.peer_keys.remove(&peer) {
+                None => return Err(RelayError::NotConnected(address)),
+                Some(key) => key,
+            },
         };
-        self.router.send(TransportMessage { address, payload });
+        self.forget_peer(&peer);
+        self.send(address, Message::ConnectionClosed { key, payload })
     }
 
-    pub(crate) fn get_protocol(&self, id: &ProtocolId) -> Option<&Protocol> {
-        match self.protocols_by_id.get(id) {
-            Some(protocol) => Some(protocol),
-            None => None,
+    pub fn send(&mut self, address: PeerAddress, message: Message) -> Result<(), RelayError> {
+        let delivery = self.delivery(&address);
+        self.send_with(address, message, delivery)
+    }
+
+    // fails with `RelayError::QueueFull` if the transport can't take the
+    // message right now
+    pub fn send_with(
+        &mut self,
+        address: PeerAddress,
+        message: Message,
+        delivery: Delivery,
+    ) -> Result<(), RelayError> {
+        debug!(peer = ?address, message = message.kind(), ?delivery, "sending");
+        self.announce(&address, delivery)?;
+        let (version, codec) = self.wire_format(&address);
+        let payload = wire::encode(&message, version, codec)?;
+        self.send_frame(address, payload, delivery)
+    }
+
+    fn send_frame(
+        &mut self,
+        address: PeerAddress,
+        payload: Bytes,
+        delivery: Delivery,
+    ) -> Result<(), RelayError> {
+        let (payload, delivery) = match self.seal(&address, payload, delivery)? {
+            Some(sealed) => sealed,
+            None => return Ok(()), // goes out once the handshake is done
+        };
+        self.router
+            .try_send(TransportMessage { address, payload }, delivery)
+    }
+
+    // lead with the versions we speak the first time we send to `address`
+    fn announce(&mut self, address: &PeerAddress, delivery: Delivery) -> Result<(), RelayError> {
+        let frame = match self.versions.announce(address) {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        let sent = self.send_frame(address.clone(), frame, delivery);
+        if sent.is_err() {
+            self.versions.remove(address); // try again with the next frame
+        }
+        sent
+    }
+
+    // queue up a send behind the ones already waiting on `address`, or start
+    // it right away if there are none
+    fn send_in_order(&mut self, address: PeerAddress, waiting: Waiting) {
+        match self.waiting.get_mut(&address) {
+            Some(queue) => queue.push_back(waiting),
+            None => {
+                self.waiting.insert(address.clone(), VecDeque::new());
+                self.start_sending(address, waiting);
+            }
+        }
+    }
+
+    // waits for room off the listen loop, a backed up transport shouldn't
+    // hold up everything else
+    fn start_sending(&mut self, address: PeerAddress, waiting: Waiting) {
+        let Waiting {
+            message,
+            delivery,
+            result_tx,
+        } = waiting;
+        let sending = self.send_when_ready(address.clone(), message, delivery);
+        self.sending.push(
+            async move {
+                let _ = result_tx.send(sending.await);
+                address
+            }
+            .boxed(),
+        );
+    }
+
+    // the send at the head of `address`'s queue is on its way, start the next
+    fn send_next(&mut self, address: PeerAddress) {
+        let next = self.waiting.get_mut(&address).and_then(VecDeque::pop_front);
+        match next {
+            Some(waiting) => self.start_sending(address, waiting),
+            None => {
+                self.waiting.remove(&address);
+            }
+        }
+    }
+
+    // same as `send_with`, but resolves once the transport has room for the
+    // message instead
+    fn send_when_ready(
+        &mut self,
+        address: PeerAddress,
+        message: Message,
+        delivery: Delivery,
+    ) -> BoxFuture<'static, Result<(), RelayError>> {
+        debug!(peer = ?address, message = message.kind(), ?delivery, "sending");
+        let sealed = self
+            .announce(&address, delivery)
+            .and_then(|()| {
+                let (version, codec) = self.wire_format(&address);
+                wire::encode(&message, version, codec)
+            })
+            .and_then(|payload| self.seal(&address, payload, delivery));
+        match sealed {
+            Ok(Some((payload, delivery))) => self
+                .router
+                .send(TransportMessage { address, payload }, delivery),
+            Ok(None) => future::ready(Ok(())).boxed(),
+            Err(err) => future::ready(Err(err)).boxed(),
+        }
+    }
+
+    // encrypt a frame if the node has a keypair; `None` means it's waiting on
+    // the handshake with `address`
+    fn seal(
+        &mut self,
+        address: &PeerAddress,
+        payload: Bytes,
+        delivery: Delivery,
+    ) -> Result<Option<(Bytes, Delivery)>, RelayError> {
+        let handshake = self.delivery(address);
+        match &mut self.sessions {
+            None => Ok(Some((payload, delivery))),
+            Some(sessions) => sessions.seal(address, payload, delivery, handshake),
+        }
+    }
+
+    // every message to `address` (handshakes included) is sent with
+    // `delivery` from now on, unless a send asks for something else
+    pub fn set_delivery(&mut self, address: PeerAddress, delivery: Delivery) {
+        self.peer_delivery.insert(address, delivery);
+    }
+
+    pub fn delivery(&self, address: &PeerAddress) -> Delivery {
+        if let Some(delivery) = self.peer_delivery.get(address) {
+            return *delivery;
         }
+        match address {
+            // unix datagram sockets can't deliver reliably
+            PeerAddress::Unix {
+                protocol: TransportProtocol::Datagram,
+                ..
+            } => Delivery::BestEffort,
+            _ => self.delivery,
+        }
+    }
+
+    pub(crate) fn get_protocol(&self, id: &ProtocolId) -> Option<&Protocol> {
+        self.registry.get(id)
     }
     pub(crate) fn get_protocol_mut(&mut self, id: &ProtocolId) -> Option<&mut Protocol> {
-        match self.protocols_by_id.get_mut(id) {
-            Some(protocol) => Some(protocol),
-            None => None,
+        self.registry.get_mut(id)
+    }
+
+    pub(crate) fn borrow_delegate(&self) -> &dyn AsyncDelegate {
+        self.delegate.as_ref()
+    }
+
+    // wait for `future` to resolve, then apply its output to the node and send
+    // the resulting response (if any) back to `address`; futures that are
+    // already resolved are applied right away, until then everything else
+    // `peer` sends waits
+    pub(crate) fn defer<T, F>(
+        &mut self,
+        peer: PeerId,
+        address: PeerAddress,
+        future: BoxFuture<'static, T>,
+        apply: F,
+    ) where
+        T: Send + 'static,
+        F: FnOnce(&mut Node, T) -> Response + Send + 'static,
+    {
+        let mut future = future;
+        let mut context = Context::from_waker(noop_waker_ref());
+        if let Poll::Ready(output) = future.poll_unpin(&mut context) {
+            let response = apply(self, output);
+            self.respond(address, response);
+            return;
         }
+
+        self.held.entry(peer.clone()).or_default();
+        let deferred = future.map(move |output| -> Deferred {
+            Box::new(move |node: &mut Node| {
+                let response = apply(node, output);
+                node.respond(address, response);
+                node.release(peer);
+            })
+        });
+        self.deferred.push(deferred.boxed());
     }
 
-    pub(crate) fn borrow_delegate(&self) -> &Box<dyn Delegate> {
-        &self.delegate
+    fn respond(&mut self, address: PeerAddress, response: Response) {
+        let result = match response {
+            Ok(Some(message)) => self.send(address, message),
+            Ok(None) => Ok(()),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            debug!(error = %err, "couldn't handle relay message");
+        }
     }
 
-    pub(crate) fn get_protocol_id(&self, key: ProtocolKey) -> Option<&ProtocolId> {
-        self.ids_by_key.get(&key)
+    // drive a future that doesn't need to report back to the node
+    pub(crate) fn drive(&mut self, future: BoxFuture<'static, ()>) {
+        let mut future = future;
+        let mut context = Context::from_waker(noop_waker_ref());
+        if future.poll_unpin(&mut context).is_pending() {
+            let deferred = future.map(|()| -> Deferred { Box::new(|_| {}) });
+            self.deferred.push(deferred.boxed());
+        }
     }
 
-    fn get_next_key(&mut self) -> ProtocolKey {
-        self.last_key += 1;
-        return self.last_key;
+    pub(crate) fn set_peer_address(&mut self, peer: PeerId, address: PeerAddress) {
+        self.peer_addresses.insert(peer, address);
+    }
+
+    // stop tracking where `peer` is once no protocol is connected to it
+    pub(crate) fn forget_peer(&mut self, peer: &PeerId) {
+        let connected = self
+            .registry
+            .ids()
+            .filter_map(|id| self.registry.get(id))
+            .any(|protocol| protocol.peer_keys.contains_key(peer));
+        if !connected {
+            self.peer_addresses.remove(peer);
+        }
+    }
+
+    pub(crate) fn negotiations_mut(&mut self) -> &mut Negotiations {
+        &mut self.negotiations
+    }
+
+    fn peer_key(
+        &self,
+        address: &PeerAddress,
+        protocol_id: &ProtocolId,
+    ) -> Result<ProtocolKey, RelayError> {
+        let protocol = match self.get_protocol(protocol_id) {
+            None => return Err(RelayError::UnknownProtocolId(protocol_id.clone())),
+            Some(protocol) => protocol,
+        };
+        let key = self
+            .peer_id(address)
+            .and_then(|peer| protocol.peer_keys.get(&peer));
+        match key {
+            None => Err(RelayError::NotConnected(address.clone())),
+            Some(key) => Ok(*key),
+        }
+    }
+
+    pub(crate) fn get_protocol_id(&self, key: ProtocolKey) -> Option<&ProtocolId> {
+        self.registry.get_id(key)
     }
 }
@@ -0,0 +1,239 @@
+use super::{AsyncDelegate, Delegate, Node, SyncDelegate};
+use crate::codec::Codec;
+use crate::heartbeat::Config as HeartbeatConfig;
+use crate::negotiation::{DEFAULT_NEGOTIATION_TIMEOUT, DEFAULT_PAGE_SIZE};
+use crate::noise::Keypair;
+use crate::transport::{Config as TransportConfig, Delivery, Overflow, Transport};
+use crate::RelayError;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub(crate) struct Config {
+    pub(crate) transport: TransportConfig,
+    pub(crate) page_size: usize,
+    pub(crate) negotiation_timeout: Duration,
+    pub(crate) delivery: Delivery,
+    pub(crate) transports: Vec<Box<dyn Transport>>,
+    pub(crate) keypair: Option<Keypair>,
+    pub(crate) heartbeat: Option<HeartbeatConfig>,
+    pub(crate) codec: Codec,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            transport: TransportConfig::default(),
+            page_size: DEFAULT_PAGE_SIZE,
+            negotiation_timeout: DEFAULT_NEGOTIATION_TIMEOUT,
+            delivery: Delivery::default(),
+            transports: Vec::new(),
+            keypair: None,
+            heartbeat: None,
+            codec: Codec::default(),
+        }
+    }
+}
+
+pub struct NodeBuilder {
+    delegate: Box<dyn AsyncDelegate>,
+    config: Config,
+}
+
+impl NodeBuilder {
+    pub fn new(delegate: Box<dyn Delegate>) -> NodeBuilder {
+        NodeBuilder::with_async_delegate(Box::new(SyncDelegate(delegate)))
+    }
+
+    pub fn with_async_delegate(delegate: Box<dyn AsyncDelegate>) -> NodeBuilder {
+        NodeBuilder {
+            delegate,
+            config: Config::default(),
+        }
+    }
+
+    // sets the ip of both the tcp and udp listeners
+    pub fn bind_ip(mut self, ip: IpAddr) -> NodeBuilder {
+        self.config.transport.tcp_address.set_ip(ip);
+        self.config.transport.udp_address.set_ip(ip);
+        self
+    }
+
+    // sets the port of both the tcp and udp listeners, 0 binds an ephemeral port
+    pub fn port(mut self, port: u16) -> NodeBuilder {
+        self.config.transport.tcp_address.set_port(port);
+        self.config.transport.udp_address.set_port(port);
+        self
+    }
+
+    pub fn tcp_address(mut self, address: SocketAddr) -> NodeBuilder {
+        self.config.transport.tcp_address = address;
+        self
+    }
+
+    pub fn tcp_port(mut self, port: u16) -> NodeBuilder {
+        self.config.transport.tcp_address.set_port(port);
+        self
+    }
+
+    pub fn udp_address(mut self, address: SocketAddr) -> NodeBuilder {
+        self.config.transport.udp_address = address;
+        self
+    }
+
+    pub fn udp_port(mut self, port: u16) -> NodeBuilder {
+        self.config.transport.udp_address.set_port(port);
+        self
+    }
+
+    // don't bind the tcp listener, peers can't reach us over tcp and we
+    // can't reach them
+    pub fn without_tcp(mut self) -> NodeBuilder {
+        self.config.transport.tcp = false;
+        self
+    }
+
+    // don't bind the udp socket, peers can't reach us over udp and we can't
+    // reach them
+    pub fn without_udp(mut self) -> NodeBuilder {
+        self.config.transport.udp = false;
+        self
+    }
+
+    // bytes allocated at a time for received datagrams, which are split off
+    // that block as they arrive; a block always has room for a full datagram
+    pub fn udp_buffer_size(mut self, buffer_size: usize) -> NodeBuilder {
+        self.config.transport.udp_buffer_size = buffer_size;
+        self
+    }
+
+    // largest datagram we send, bigger frames are split into fragments
+    pub fn udp_mtu(mut self, mtu: usize) -> NodeBuilder {
+        self.config.transport.udp_mtu = mtu;
+        self
+    }
+
+    // how long to wait for the rest of a fragmented frame before dropping it
+    pub fn udp_reassembly_timeout(mut self, timeout: Duration) -> NodeBuilder {
+        self.config.transport.udp_reassembly.timeout = timeout;
+        self
+    }
+
+    // bytes of incomplete frames kept per sender and across all senders, the
+    // oldest frames are dropped to make room
+    pub fn udp_reassembly_limits(mut self, per_peer: usize, total: usize) -> NodeBuilder {
+        self.config.transport.udp_reassembly.per_peer = per_peer;
+        self.config.transport.udp_reassembly.total = total;
+        self
+    }
+
+    // how long to wait for the first acknowledgment of a reliable datagram,
+    // and how many times to send it before giving up; the wait doubles with
+    // every attempt, up to a minute, and a datagram we give up on is only
+    // logged
+    pub fn udp_retransmission(mut self, timeout: Duration, max_attempts: u32) -> NodeBuilder {
+        self.config.transport.udp_reliability.initial_timeout = timeout;
+        self.config.transport.udp_reliability.max_attempts = max_attempts;
+        self
+    }
+
+    // delivery used for udp peers that haven't been given their own through
+    // `Node::set_delivery`, unix datagram peers default to best effort
+    pub fn datagram_delivery(mut self, delivery: Delivery) -> NodeBuilder {
+        self.config.delivery = delivery;
+        self
+    }
+
+    // binds a unix stream listener at `path`, which must not exist yet
+    pub fn unix_stream_path(mut self, path: impl Into<PathBuf>) -> NodeBuilder {
+        self.config.transport.unix_stream_path = Some(path.into());
+        self
+    }
+
+    // binds a unix datagram socket at `path`, which must not exist yet
+    pub fn unix_datagram_path(mut self, path: impl Into<PathBuf>) -> NodeBuilder {
+        self.config.transport.unix_datagram_path = Some(path.into());
+        self
+    }
+
+    // how many frames each transport queues up on their way out, and on
+    // their way in to the node, and how many calls handles queue up for it;
+    // senders wait (or get `RelayError::QueueFull`) once a queue is full
+    pub fn queue_capacity(mut self, capacity: usize) -> NodeBuilder {
+        self.config.transport.queues.capacity = capacity.max(1);
+        self
+    }
+
+    // how many frames wait to be written to a single stream connection, and
+    // what happens to a peer that lets that queue fill up
+    pub fn peer_queue(mut self, capacity: usize, overflow: Overflow) -> NodeBuilder {
+        self.config.transport.queues.peer_capacity = capacity.max(1);
+        self.config.transport.queues.overflow = overflow;
+        self
+    }
+
+    // adds a transport for peers with its address scheme, which mustn't be
+    // one the node already has a transport for
+    pub fn transport(mut self, transport: Box<dyn Transport>) -> NodeBuilder {
+        self.config.transports.push(transport);
+        self
+    }
+
+    // encrypts all traffic, after a handshake that proves to each peer we
+    // hold `keypair`; peers have to be configured with a keypair as well
+    pub fn keypair(mut self, keypair: Keypair) -> NodeBuilder {
+        self.config.keypair = Some(keypair);
+        self
+    }
+
+    // ping peers a protocol is connected to once they've been quiet for
+    // `interval`, and drop them (closing their protocol connections) once
+    // they've been quiet for `timeout`, which has to be longer; nodes don't
+    // send heartbeats unless asked to (15 and 45 seconds suit most)
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> NodeBuilder {
+        self.config.heartbeat = Some(HeartbeatConfig {
+            interval: interval.max(Duration::from_millis(1)),
+            timeout,
+        });
+        self
+    }
+
+    // never ping peers or give up on quiet ones, the default
+    pub fn without_heartbeat(mut self) -> NodeBuilder {
+        self.config.heartbeat = None;
+        self
+    }
+
+    // how the messages we send are encoded; we decode every codec
+    // regardless, and answer peers with the codec they send with
+    pub fn codec(mut self, codec: Codec) -> NodeBuilder {
+        self.config.codec = codec;
+        self
+    }
+
+    // size budget (in bytes of protocol ids and payload) for each page of
+    // proposals sent by `Node::send_negotiable`
+    pub fn negotiation_page_size(mut self, page_size: usize) -> NodeBuilder {
+        self.config.page_size = page_size;
+        self
+    }
+
+    // how long a negotiation waits for each answer from the peer before it
+    // fails
+    pub fn negotiation_timeout(mut self, timeout: Duration) -> NodeBuilder {
+        self.config.negotiation_timeout = timeout;
+        self
+    }
+
+    pub async fn build(self) -> Result<Node, RelayError> {
+        if let Some(heartbeat) = &self.config.heartbeat {
+            // a peer would be given up on before we ever pinged it
+            if heartbeat.timeout <= heartbeat.interval {
+                return Err(RelayError::InvalidConfig(format!(
+                    "heartbeat timeout {:?} isn't longer than its interval {:?}",
+                    heartbeat.timeout, heartbeat.interval
+                )));
+            }
+        }
+        Node::with_config(self.delegate, self.config).await
+    }
+}
@@ -0,0 +1,219 @@
+use crate::message::{Payload, ProtocolId};
+use crate::negotiation::{NegotiationOutcome, Proposal};
+use crate::transport::{Delivery, PeerAddress};
+use crate::RelayError;
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    oneshot,
+};
+
+pub(crate) enum Command {
+    Connect {
+        address: PeerAddress,
+        protocol_id: ProtocolId,
+        payload: Payload,
+        result_tx: ResultTx,
+    },
+    Send {
+        address: PeerAddress,
+        protocol_id: ProtocolId,
+        payload: Payload,
+        delivery: Option<Delivery>,
+        // wait for room in the transport's queue, or fail right away
+        wait: bool,
+        result_tx: ResultTx,
+    },
+    SetDelivery {
+        address: PeerAddress,
+        delivery: Delivery,
+    },
+    Close {
+        address: PeerAddress,
+        protocol_id: ProtocolId,
+        payload: Payload,
+        result_tx: ResultTx,
+    },
+    Negotiate {
+        address: PeerAddress,
+        proposals: Vec<Proposal>,
+        // told whether the proposals went out, then what came of them
+        result_tx: ResultTx,
+        outcome_tx: oneshot::Sender<NegotiationOutcome>,
+    },
+    Shutdown {
+        done_tx: oneshot::Sender<()>,
+    },
+}
+
+pub(crate) type ResultTx = oneshot::Sender<Result<(), RelayError>>;
+
+pub(crate) type CommandTx = Sender<Command>;
+pub(crate) type CommandRx = Receiver<Command>;
+
+/*
+
+    A handle lets anything (protocol handlers in particular) talk to peers
+    without owning the node. Every call is queued up for the node, which
+    carries it out the next time its listen loop comes around. The queue
+    holds `NodeBuilder::queue_capacity` calls, once it's full callers wait
+    for the node to catch up.
+
+*/
+#[derive(Clone)]
+pub struct NodeHandle {
+    command_tx: CommandTx,
+}
+
+impl NodeHandle {
+    pub(crate) fn new(command_tx: CommandTx) -> NodeHandle {
+        NodeHandle { command_tx }
+    }
+
+    pub async fn connect(
+        &self,
+        address: PeerAddress,
+        protocol_id: ProtocolId,
+        payload: Payload,
+    ) -> Result<(), RelayError> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let command = Command::Connect {
+            address,
+            protocol_id,
+            payload,
+            result_tx,
+        };
+        self.request(command, result_rx).await
+    }
+
+    pub async fn send(
+        &self,
+        address: PeerAddress,
+        protocol_id: ProtocolId,
+        payload: Payload,
+    ) -> Result<(), RelayError> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let command = Command::Send {
+            address,
+            protocol_id,
+            payload,
+            delivery: None,
+            wait: true,
+            result_tx,
+        };
+        self.request(command, result_rx).await
+    }
+
+    // same as `send`, but fails with `RelayError::QueueFull` instead of
+    // waiting when the peer's transport is backed up, or earlier sends to
+    // the peer are still waiting
+    pub async fn try_send(
+        &self,
+        address: PeerAddress,
+        protocol_id: ProtocolId,
+        payload: Payload,
+    ) -> Result<(), RelayError> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let command = Command::Send {
+            address,
+            protocol_id,
+            payload,
+            delivery: None,
+            wait: false,
+            result_tx,
+        };
+        self.request(command, result_rx).await
+    }
+
+    pub async fn send_with(
+        &self,
+        address: PeerAddress,
+        protocol_id: ProtocolId,
+        payload: Payload,
+        delivery: Delivery,
+    ) -> Result<(), RelayError> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let command = Command::Send {
+            address,
+            protocol_id,
+            payload,
+            delivery: Some(delivery),
+            wait: true,
+            result_tx,
+        };
+        self.request(command, result_rx).await
+    }
+
+    // resolves once the node has taken the setting up, nothing is
+    // configured if it's gone
+    pub async fn set_delivery(&self, address: PeerAddress, delivery: Delivery) {
+        let command = Command::SetDelivery { address, delivery };
+        let _ = self.command_tx.send(command).await;
+    }
+
+    pub async fn close(
+        &self,
+        address: PeerAddress,
+        protocol_id: ProtocolId,
+        payload: Payload,
+    ) -> Result<(), RelayError> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let command = Command::Close {
+            address,
+            protocol_id,
+            payload,
+            result_tx,
+        };
+        self.request(command, result_rx).await
+    }
+
+    // resolves with what the peer made of the proposals, or an error if
+    // they couldn't be sent
+    pub async fn send_negotiable(
+        &self,
+        address: PeerAddress,
+        proposals: Vec<Proposal>,
+    ) -> Result<NegotiationOutcome, RelayError> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let (outcome_tx, outcome_rx) = oneshot::channel();
+        let command = Command::Negotiate {
+            address,
+            proposals,
+            result_tx,
+            outcome_tx,
+        };
+        self.request(command, result_rx).await?;
+        match outcome_rx.await {
+            Ok(outcome) => Ok(outcome),
+            Err(_) => Err(RelayError::NodeStopped),
+        }
+    }
+
+    // resolves once the node has closed every protocol connection and all of
+    // its transports have stopped, or right away if the node is already gone
+    pub async fn shutdown(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self
+            .command_tx
+            .send(Command::Shutdown { done_tx })
+            .await
+            .is_err()
+        {
+            return;
+        }
+        let _ = done_rx.await;
+    }
+
+    async fn request(
+        &self,
+        command: Command,
+        result_rx: oneshot::Receiver<Result<(), RelayError>>,
+    ) -> Result<(), RelayError> {
+        if self.command_tx.send(command).await.is_err() {
+            return Err(RelayError::NodeStopped);
+        }
+        match result_rx.await {
+            Ok(result) => result,
+            Err(_) => Err(RelayError::NodeStopped),
+        }
+    }
+}
@@ -0,0 +1,743 @@
+use crate::transport::{Delivery, PeerAddress};
+use crate::RelayError;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::future;
+use snow::{
+    params::DHChoice,
+    resolvers::{CryptoResolver, DefaultResolver},
+    Builder, HandshakeState, StatelessTransportState,
+};
+use std::{collections::HashMap, fmt, time::Duration};
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+use tracing::debug;
+
+/*
+
+    Once a node has a static keypair, every frame it exchanges with a peer
+    goes through a Noise XX handshake first and is encrypted afterwards.
+    Frames start with their kind
+
+        hello      0u8, handshake message 1 (-> e)
+        reply      1u8, handshake message 2 (<- e, ee, s, es)
+        finish     2u8, handshake message 3 (-> s, se)
+        sealed     3u8, then records of nonce u64, length u16, ciphertext
+
+    A frame too large for a single Noise message is split over several
+    records. Nonces are explicit so frames can arrive out of order (or not
+    at all) over datagrams, and a sliding window rejects replayed ones.
+    Handshake messages go out however the node delivers to the peer, so
+    over a transport that can't deliver reliably a lost one holds the
+    handshake up until it times out.
+
+    Frames queued up while the handshake is running go out once it
+    finishes. When both peers dial each other at once, the one whose hello
+    sorts lower stays the initiator and the other answers it. A hello from
+    a peer we already have a session with means it started over, so we
+    answer, but keep using the old session until the new one is done: a
+    hello proves nothing about who sent it. For the same reason we never
+    say hello in response to a sealed frame we can't open, and handshakes
+    we answer are bounded and given up on after `HANDSHAKE_TIMEOUT`.
+
+*/
+
+const PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+const HELLO: u8 = 0;
+const REPLY: u8 = 1;
+const FINISH: u8 = 2;
+const SEALED: u8 = 3;
+
+pub const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const MAX_MESSAGE_LEN: usize = 65535;
+const MAX_RECORD_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+// nonce and length
+const RECORD_HEADER_LEN: usize = 10;
+
+// how many frames wait on a handshake in each direction before we give up
+// on new ones
+const PENDING_LIMIT: usize = 256;
+// a handshake that hasn't finished in this long is given up on, or started
+// over if we have frames for the peer
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// how many handshakes can be running at once before we ignore new hellos
+const MAX_HANDSHAKES: usize = 1024;
+// how far behind the newest nonce we still accept frames
+const REPLAY_WINDOW: u64 = 1024;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct PublicKey([u8; KEY_LEN]);
+impl PublicKey {
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> PublicKey {
+        PublicKey(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    fn from_slice(bytes: &[u8]) -> Option<PublicKey> {
+        bytes.try_into().ok().map(PublicKey)
+    }
+}
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+// a node's static curve25519 keypair, peers learn the public half during the
+// handshake
+#[derive(Clone)]
+pub struct Keypair {
+    private: [u8; KEY_LEN],
+    public: PublicKey,
+}
+impl Keypair {
+    pub fn generate() -> Keypair {
+        let builder = Builder::new(params());
+        // only fails if the system has no source of randomness at all
+        let keypair = builder
+            .generate_keypair()
+            .expect("couldn't generate a keypair");
+        let mut private = [0; KEY_LEN];
+        private.copy_from_slice(&keypair.private);
+        Keypair::from_private(private)
+    }
+
+    pub fn from_private(private: [u8; KEY_LEN]) -> Keypair {
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .expect("curve25519 is always available");
+        dh.set(&private);
+        let public = PublicKey::from_slice(dh.pubkey()).expect("curve25519 keys are 32 bytes");
+        Keypair { private, public }
+    }
+
+    pub fn public(&self) -> &PublicKey {
+        &self.public
+    }
+}
+impl fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keypair")
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+fn params() -> snow::params::NoiseParams {
+    PARAMS.parse().expect("noise parameters are valid")
+}
+
+fn encryption_error(err: snow::Error) -> RelayError {
+    RelayError::Encryption(err.to_string())
+}
+
+fn handshake_frame(kind: u8, message: &[u8], delivery: Delivery) -> (Bytes, Delivery) {
+    let mut frame = BytesMut::with_capacity(1 + message.len());
+    frame.put_u8(kind);
+    frame.put_slice(message);
+    (frame.freeze(), delivery)
+}
+
+// frames for the transports, along with how they should be delivered
+pub(crate) type Frames = Vec<(Bytes, Delivery)>;
+
+// what came of a frame we received
+#[derive(Default)]
+pub(crate) struct Opened {
+    // relay frames to hand to the node, in order
+    pub(crate) frames: Vec<Bytes>,
+    // frames to send back to the peer
+    pub(crate) replies: Frames,
+    // set when the handshake with the peer just finished
+    pub(crate) secured: Option<PublicKey>,
+}
+
+struct Handshake {
+    state: HandshakeState,
+    // our hello, kept while we're waiting for a reply to it
+    hello: Option<Bytes>,
+    started: Instant,
+    outbound: Frames,
+    inbound: Vec<Bytes>,
+}
+
+struct Session {
+    transport: StatelessTransportState,
+    remote_key: PublicKey,
+    next_nonce: u64,
+    replay: Replay,
+}
+
+// the session we have with a peer, and the handshake for a new one; the
+// session stays in use until the handshake replacing it is done
+#[derive(Default)]
+struct Peer {
+    session: Option<Session>,
+    handshake: Option<Box<Handshake>>,
+}
+
+pub(crate) struct Sessions {
+    keypair: Keypair,
+    peers: HashMap<PeerAddress, Peer>,
+    ticker: Interval,
+}
+
+// resolves whenever it's time to look for stale handshakes, or never
+// without a keypair
+pub(crate) async fn tick(sessions: &mut Option<Sessions>) {
+    match sessions {
+        Some(sessions) => {
+            sessions.ticker.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+impl Sessions {
+    pub(crate) fn new(keypair: Keypair) -> Sessions {
+        // look for stale handshakes twice per timeout
+        let period = HANDSHAKE_TIMEOUT / 2;
+        let mut ticker = time::interval_at(Instant::now() + period, period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Sessions {
+            keypair,
+            peers: HashMap::new(),
+            ticker,
+        }
+    }
+
+    pub(crate) fn public_key(&self) -> &PublicKey {
+        self.keypair.public()
+    }
+
+    pub(crate) fn remote_key(&self, address: &PeerAddress) -> Option<&PublicKey> {
+        let session = self.peers.get(address)?.session.as_ref()?;
+        Some(&session.remote_key)
+    }
+
+    // every address we have a session with, and the key the peer proved
+    pub(crate) fn established(&self) -> impl Iterator<Item = (&PeerAddress, &PublicKey)> {
+        self.peers.iter().filter_map(|(address, peer)| {
+            let session = peer.session.as_ref()?;
+            Some((address, &session.remote_key))
+        })
+    }
+
+    // the transport connection is gone, so is whatever we had going with it
+    pub(crate) fn remove(&mut self, address: &PeerAddress) {
+        self.peers.remove(address);
+    }
+
+    // give up on handshakes that haven't finished in time, along with the
+    // frames waiting on them
+    pub(crate) fn expire(&mut self) {
+        self.peers.retain(|address, peer| {
+            let stale = peer
+                .handshake
+                .as_ref()
+                .is_some_and(|handshake| handshake.started.elapsed() >= HANDSHAKE_TIMEOUT);
+            if stale {
+                debug!(peer = ?address, "handshake timed out");
+                peer.handshake = None;
+            }
+            peer.session.is_some() || peer.handshake.is_some()
+        });
+    }
+
+    // encrypts a relay frame for `address`, or holds on to it until the
+    // handshake is done; returns whatever has to be sent right away, a
+    // hello going out with `handshake` delivery
+    pub(crate) fn seal(
+        &mut self,
+        address: &PeerAddress,
+        bytes: Bytes,
+        delivery: Delivery,
+        handshake: Delivery,
+    ) -> Result<Option<(Bytes, Delivery)>, RelayError> {
+        let peer = self.peers.entry(address.clone()).or_default();
+        if let Some(session) = &mut peer.session {
+            let sealed = session.seal(&bytes)?;
+            return Ok(Some((sealed, delivery)));
+        }
+        let outbound = match &mut peer.handshake {
+            Some(handshake) => {
+                if handshake.outbound.len() >= PENDING_LIMIT {
+                    return Err(RelayError::QueueFull(address.clone()));
+                }
+                handshake.outbound.push((bytes, delivery));
+                if handshake.started.elapsed() < HANDSHAKE_TIMEOUT {
+                    return Ok(None);
+                }
+                // the peer never answered, say hello again
+                std::mem::take(&mut handshake.outbound)
+            }
+            None => vec![(bytes, delivery)],
+        };
+        let hello = self.initiate(address, outbound, handshake)?;
+        Ok(Some(hello))
+    }
+
+    // start a handshake with `address` as the initiator, returning our hello
+    fn initiate(
+        &mut self,
+        address: &PeerAddress,
+        outbound: Frames,
+        delivery: Delivery,
+    ) -> Result<(Bytes, Delivery), RelayError> {
+        let mut state = Builder::new(params())
+            .local_private_key(&self.keypair.private)
+            .build_initiator()
+            .map_err(encryption_error)?;
+        let mut message = vec![0; MAX_MESSAGE_LEN];
+        let len = state
+            .write_message(&[], &mut message)
+            .map_err(encryption_error)?;
+        let hello = Bytes::copy_from_slice(&message[..len]);
+        let handshake = Handshake {
+            state,
+            hello: Some(hello.clone()),
+            started: Instant::now(),
+            outbound,
+            inbound: Vec::new(),
+        };
+        let peer = self.peers.entry(address.clone()).or_default();
+        peer.handshake = Some(Box::new(handshake));
+        Ok(handshake_frame(HELLO, &hello, delivery))
+    }
+
+    // handshake replies go out with `handshake` delivery
+    pub(crate) fn open(
+        &mut self,
+        address: &PeerAddress,
+        mut frame: Bytes,
+        handshake: Delivery,
+    ) -> Result<Opened, RelayError> {
+        if frame.is_empty() {
+            return Err(RelayError::Encryption("empty frame".to_string()));
+        }
+        match frame.get_u8() {
+            HELLO => self.hello(address, frame, handshake),
+            REPLY => self.reply(address, frame, handshake),
+            FINISH => self.finish(address, frame),
+            SEALED => self.sealed(address, frame),
+            kind => Err(RelayError::Encryption(format!(
+                "unknown frame kind {}",
+                kind
+            ))),
+        }
+    }
+
+    fn hello(
+        &mut self,
+        address: &PeerAddress,
+        hello: Bytes,
+        delivery: Delivery,
+    ) -> Result<Opened, RelayError> {
+        let current = self
+            .peers
+            .get(address)
+            .and_then(|peer| peer.handshake.as_ref());
+        // we both said hello, the lower one wins
+        let ours = current.and_then(|handshake| handshake.hello.as_ref());
+        if ours.is_some_and(|ours| *ours < hello) {
+            return Ok(Opened::default());
+        }
+        if current.is_none() && self.handshakes() >= MAX_HANDSHAKES {
+            return Err(RelayError::Encryption(
+                "too many handshakes in progress".to_string(),
+            ));
+        }
+
+        let mut state = Builder::new(params())
+            .local_private_key(&self.keypair.private)
+            .build_responder()
+            .map_err(encryption_error)?;
+        let mut payload = vec![0; MAX_MESSAGE_LEN];
+        state
+            .read_message(&hello, &mut payload)
+            .map_err(encryption_error)?;
+        let mut message = vec![0; MAX_MESSAGE_LEN];
+        let len = state
+            .write_message(&[], &mut message)
+            .map_err(encryption_error)?;
+
+        // a session we already have stays in use until this one is done
+        let peer = self.peers.entry(address.clone()).or_default();
+        let outbound = peer
+            .handshake
+            .take()
+            .map(|handshake| handshake.outbound)
+            .unwrap_or_default();
+        peer.handshake = Some(Box::new(Handshake {
+            state,
+            hello: None,
+            started: Instant::now(),
+            outbound,
+            inbound: Vec::new(),
+        }));
+        Ok(Opened {
+            replies: vec![handshake_frame(REPLY, &message[..len], delivery)],
+            ..Opened::default()
+        })
+    }
+
+    fn reply(
+        &mut self,
+        address: &PeerAddress,
+        reply: Bytes,
+        delivery: Delivery,
+    ) -> Result<Opened, RelayError> {
+        // only one we asked for
+        let mut handshake = match self.take_handshake(address, true) {
+            Some(handshake) => handshake,
+            None => {
+                return Err(RelayError::Encryption(
+                    "unexpected handshake reply".to_string(),
+                ))
+            }
+        };
+
+        let mut payload = vec![0; MAX_MESSAGE_LEN];
+        let mut message = vec![0; MAX_MESSAGE_LEN];
+        let written = handshake
+            .state
+            .read_message(&reply, &mut payload)
+            .and_then(|_| handshake.state.write_message(&[], &mut message));
+        let len = match written {
+            Ok(len) => len,
+            Err(err) => {
+                // not the peer's reply, keep waiting for it
+                self.put_back(address, handshake);
+                return Err(encryption_error(err));
+            }
+        };
+
+        let mut opened = self.establish(address, handshake)?;
+        opened
+            .replies
+            .insert(0, handshake_frame(FINISH, &message[..len], delivery));
+        Ok(opened)
+    }
+
+    fn finish(&mut self, address: &PeerAddress, finish: Bytes) -> Result<Opened, RelayError> {
+        let mut handshake = match self.take_handshake(address, false) {
+            Some(handshake) => handshake,
+            None => {
+                return Err(RelayError::Encryption(
+                    "unexpected handshake finish".to_string(),
+                ))
+            }
+        };
+
+        let mut payload = vec![0; MAX_MESSAGE_LEN];
+        if let Err(err) = handshake.state.read_message(&finish, &mut payload) {
+            self.put_back(address, handshake);
+            return Err(encryption_error(err));
+        }
+        self.establish(address, handshake)
+    }
+
+    // the handshake with `address`, if we're the initiator of it or not
+    fn take_handshake(&mut self, address: &PeerAddress, initiator: bool) -> Option<Box<Handshake>> {
+        let peer = self.peers.get_mut(address)?;
+        let handshake = peer
+            .handshake
+            .take_if(|handshake| handshake.hello.is_some() == initiator);
+        if handshake.is_some() && peer.session.is_none() {
+            self.peers.remove(address);
+        }
+        handshake
+    }
+
+    fn put_back(&mut self, address: &PeerAddress, handshake: Box<Handshake>) {
+        let peer = self.peers.entry(address.clone()).or_default();
+        peer.handshake = Some(handshake);
+    }
+
+    fn handshakes(&self) -> usize {
+        self.peers
+            .values()
+            .filter(|peer| peer.handshake.is_some())
+            .count()
+    }
+
+    // turn a finished handshake into a session, and deal with every frame
+    // that was waiting on it
+    fn establish(
+        &mut self,
+        address: &PeerAddress,
+        handshake: Box<Handshake>,
+    ) -> Result<Opened, RelayError> {
+        let Handshake {
+            state,
+            outbound,
+            inbound,
+            ..
+        } = *handshake;
+        let remote_key = state
+            .get_remote_static()
+            .and_then(PublicKey::from_slice)
+            .ok_or_else(|| RelayError::Encryption("peer has no static key".to_string()))?;
+        let transport = state
+            .into_stateless_transport_mode()
+            .map_err(encryption_error)?;
+        let mut session = Session {
+            transport,
+            remote_key: remote_key.clone(),
+            next_nonce: 0,
+            replay: Replay::new(),
+        };
+
+        let mut opened = Opened {
+            secured: Some(remote_key),
+            ..Opened::default()
+        };
+        for (bytes, delivery) in outbound {
+            opened.replies.push((session.seal(&bytes)?, delivery));
+        }
+        for frame in inbound {
+            // whatever doesn't decrypt was never meant for this session
+            if let Ok(frame) = session.open(frame) {
+                opened.frames.push(frame);
+            }
+        }
+        let peer = Peer {
+            session: Some(session),
+            handshake: None,
+        };
+        self.peers.insert(address.clone(), peer);
+        Ok(opened)
+    }
+
+    // frames from a peer we don't have a session with are dropped, never
+    // answered with a hello: the sender could be anyone
+    fn sealed(&mut self, address: &PeerAddress, frame: Bytes) -> Result<Opened, RelayError> {
+        let peer = match self.peers.get_mut(address) {
+            Some(peer) => peer,
+            None => return Err(RelayError::Encryption("no session".to_string())),
+        };
+        let opened = match &mut peer.session {
+            Some(session) => session.open(frame.clone()),
+            None => Err(RelayError::Encryption("no session".to_string())),
+        };
+        match (opened, &mut peer.handshake) {
+            (Ok(frame), _) => Ok(Opened {
+                frames: vec![frame],
+                ..Opened::default()
+            }),
+            // over datagrams the finish can be overtaken by the frames after
+            // it, which belong to the session we're answering for
+            (Err(_), Some(handshake)) if handshake.hello.is_none() => {
+                if handshake.inbound.len() < PENDING_LIMIT {
+                    handshake.inbound.push(frame);
+                }
+                Ok(Opened::default())
+            }
+            (Err(err), _) => Err(err),
+        }
+    }
+}
+
+impl Session {
+    fn seal(&mut self, bytes: &[u8]) -> Result<Bytes, RelayError> {
+        let records = bytes.len().div_ceil(MAX_RECORD_LEN).max(1);
+        let mut frame =
+            BytesMut::with_capacity(1 + bytes.len() + records * (RECORD_HEADER_LEN + TAG_LEN));
+        frame.put_u8(SEALED);
+
+        let mut ciphertext = vec![0; MAX_MESSAGE_LEN];
+        let mut chunks = bytes.chunks(MAX_RECORD_LEN);
+        // an empty frame still gets a record
+        let first = chunks.next().unwrap_or(&[]);
+        for chunk in std::iter::once(first).chain(chunks) {
+            let nonce = self.next_nonce;
+            self.next_nonce += 1;
+            let len = self
+                .transport
+                .write_message(nonce, chunk, &mut ciphertext)
+                .map_err(encryption_error)?;
+            frame.put_u64(nonce);
+            frame.put_u16(len as u16);
+            frame.put_slice(&ciphertext[..len]);
+        }
+        Ok(frame.freeze())
+    }
+
+    fn open(&mut self, mut frame: Bytes) -> Result<Bytes, RelayError> {
+        let mut plaintext = BytesMut::with_capacity(frame.len());
+        let mut buffer = vec![0; MAX_MESSAGE_LEN];
+        let mut nonces = Vec::new();
+        while frame.has_remaining() {
+            if frame.remaining() < RECORD_HEADER_LEN {
+                return Err(RelayError::Encryption("truncated record".to_string()));
+            }
+            let nonce = frame.get_u64();
+            let len = frame.get_u16() as usize;
+            if frame.remaining() < len {
+                return Err(RelayError::Encryption("truncated record".to_string()));
+            }
+            if !self.replay.check(nonce) || nonces.contains(&nonce) {
+                return Err(RelayError::Encryption("replayed record".to_string()));
+            }
+            let record = frame.split_to(len);
+            let len = self
+                .transport
+                .read_message(nonce, &record, &mut buffer)
+                .map_err(encryption_error)?;
+            plaintext.put_slice(&buffer[..len]);
+            nonces.push(nonce);
+        }
+        if nonces.is_empty() {
+            return Err(RelayError::Encryption("empty sealed frame".to_string()));
+        }
+
+        // only once the whole frame checks out
+        for nonce in nonces {
+            self.replay.mark(nonce);
+        }
+        Ok(plaintext.freeze())
+    }
+}
+
+// remembers which of the last `REPLAY_WINDOW` nonces we've seen
+struct Replay {
+    // one past the highest nonce seen so far
+    top: u64,
+    seen: Vec<u64>,
+}
+impl Replay {
+    fn new() -> Replay {
+        Replay {
+            top: 0,
+            seen: vec![0; (REPLAY_WINDOW / 64) as usize],
+        }
+    }
+
+    fn slot(nonce: u64) -> (usize, u64) {
+        let index = nonce % REPLAY_WINDOW;
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+
+    // false for nonces we've seen, or that are too old to tell
+    fn check(&self, nonce: u64) -> bool {
+        if nonce >= self.top {
+            return true;
+        }
+        if self.top - nonce > REPLAY_WINDOW {
+            return false;
+        }
+        let (word, bit) = Replay::slot(nonce);
+        self.seen[word] & bit == 0
+    }
+
+    fn mark(&mut self, nonce: u64) {
+        if nonce >= self.top {
+            // forget the nonces that just fell out of the window
+            let start = self.top.max((nonce + 1).saturating_sub(REPLAY_WINDOW));
+            for stale in start..=nonce {
+                let (word, bit) = Replay::slot(stale);
+                self.seen[word] &= !bit;
+            }
+            self.top = nonce + 1;
+        }
+        let (word, bit) = Replay::slot(nonce);
+        self.seen[word] |= bit;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::TransportProtocol;
+    use std::os::unix::net::SocketAddr as UnixSocketAddr;
+
+    fn datagram_address(path: &str) -> PeerAddress {
+        PeerAddress::Unix {
+            address: UnixSocketAddr::from_pathname(path).unwrap(),
+            protocol: TransportProtocol::Datagram,
+        }
+    }
+
+    #[test]
+    fn replay_accepts_each_nonce_once() {
+        let mut replay = Replay::new();
+        for nonce in [0, 2, 1, 5] {
+            assert!(replay.check(nonce));
+            replay.mark(nonce);
+            assert!(!replay.check(nonce));
+        }
+        // skipped, but still in the window
+        assert!(replay.check(3));
+        assert!(replay.check(4));
+        assert!(!replay.check(2));
+    }
+
+    #[test]
+    fn replay_rejects_nonces_that_fell_out_of_the_window() {
+        let mut replay = Replay::new();
+        replay.mark(0);
+        replay.mark(REPLAY_WINDOW);
+        // never seen, but too old to tell
+        assert!(!replay.check(0));
+        assert!(replay.check(1));
+        assert!(!replay.check(REPLAY_WINDOW));
+    }
+
+    #[test]
+    fn replay_forgets_what_it_saw_once_the_window_moves_past_it() {
+        let mut replay = Replay::new();
+        replay.mark(1);
+        // shares a slot with 1, which has to be cleared for it
+        replay.mark(REPLAY_WINDOW + 2);
+        assert!(!replay.check(REPLAY_WINDOW + 2));
+        assert!(replay.check(REPLAY_WINDOW + 1));
+        // a jump clear across the window forgets everything before it
+        replay.mark(4 * REPLAY_WINDOW);
+        for nonce in 3 * REPLAY_WINDOW + 1..4 * REPLAY_WINDOW {
+            assert!(replay.check(nonce));
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_frames_go_out_with_the_delivery_asked_for() {
+        let (a_address, b_address) = (datagram_address("/a"), datagram_address("/b"));
+        let mut a = Sessions::new(Keypair::generate());
+        let mut b = Sessions::new(Keypair::generate());
+
+        let frame = Bytes::from_static(b"frame");
+        let sealed = a.seal(
+            &b_address,
+            frame.clone(),
+            Delivery::BestEffort,
+            Delivery::BestEffort,
+        );
+        let (hello, delivery) = sealed.unwrap().unwrap();
+        assert_eq!(delivery, Delivery::BestEffort);
+
+        let opened = b.open(&a_address, hello, Delivery::BestEffort).unwrap();
+        let (reply, delivery) = opened.replies.into_iter().next().unwrap();
+        assert_eq!(delivery, Delivery::BestEffort);
+
+        let opened = a.open(&b_address, reply, Delivery::BestEffort).unwrap();
+        assert!(opened.secured.as_ref() == Some(b.public_key()));
+        let mut replies = opened.replies.into_iter();
+        let (finish, delivery) = replies.next().unwrap();
+        assert_eq!(delivery, Delivery::BestEffort);
+        let (sealed, _) = replies.next().unwrap();
+
+        let opened = b.open(&a_address, finish, Delivery::BestEffort).unwrap();
+        assert!(opened.secured.as_ref() == Some(a.public_key()));
+        let opened = b
+            .open(&a_address, sealed.clone(), Delivery::BestEffort)
+            .unwrap();
+        assert_eq!(opened.frames, vec![frame]);
+        // the same frame again is a replay
+        assert!(b.open(&a_address, sealed, Delivery::BestEffort).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+use crate::noise::PublicKey;
+use crate::transport::PeerAddress;
+use snow::{
+    params::HashChoice,
+    resolvers::{CryptoResolver, DefaultResolver},
+};
+use std::fmt;
+
+/*
+
+    Who a peer is, as opposed to where it currently is. A node with a
+    keypair derives every peer's id from the static key the peer proved it
+    holds during the handshake, so a peer keeps its id when it reconnects
+    from somewhere else, and nobody else can take it over. Without a
+    keypair there is nothing to prove, and the id is just the address the
+    peer was reached at; it's a variant of its own so handlers can tell
+    the two apart.
+
+*/
+
+pub const PEER_ID_LEN: usize = 32;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum PeerId {
+    // a digest of the static key the peer proved it holds
+    Key([u8; PEER_ID_LEN]),
+    // where a peer that proved nothing was reached
+    Address(PeerAddress),
+}
+
+impl PeerId {
+    pub fn from_public_key(key: &PublicKey) -> PeerId {
+        let mut hash = DefaultResolver
+            .resolve_hash(&HashChoice::Blake2s)
+            .expect("blake2s is always available");
+        hash.input(b"key");
+        hash.input(key.as_bytes());
+        let mut id = [0; PEER_ID_LEN];
+        hash.result(&mut id);
+        PeerId::Key(id)
+    }
+
+    pub(crate) fn from_address(address: &PeerAddress) -> PeerId {
+        PeerId::Address(address.clone())
+    }
+
+    // true if the peer proved who it is with a static key
+    pub fn is_verified(&self) -> bool {
+        matches!(self, PeerId::Key(_))
+    }
+
+    // the key digest, only for verified peers
+    pub fn as_bytes(&self) -> Option<&[u8; PEER_ID_LEN]> {
+        match self {
+            PeerId::Key(id) => Some(id),
+            PeerId::Address(_) => None,
+        }
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerId::Key(id) => {
+                for byte in id {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+            PeerId::Address(address) => write!(f, "{:?}", address),
+        }
+    }
+}
@@ -1,18 +1,155 @@
 use crate::message::ProtocolKey;
+use crate::noise::PublicKey;
+use crate::peer::PeerId;
 use crate::transport::PeerAddress;
 use crate::Payload;
+use futures::future::{self, BoxFuture, FutureExt};
 use std::collections::HashMap;
 
-pub trait Handler {
-    fn handle_message(&self, address: PeerAddress, payload: Payload);
-    fn verify_accepted_connection(&self, address: PeerAddress, payload: Payload)
-        -> Option<Payload>;
-    fn verify_confirmed_connection(&self, address: PeerAddress, payload: Payload) -> bool;
-    fn verify_closed_connection(&self, address: PeerAddress, payload: Payload) -> bool;
+pub(crate) mod registry;
+
+pub trait Handler: Send {
+    fn handle_message(&self, peer: PeerId, address: PeerAddress, payload: Payload);
+    fn verify_requested_connection(
+        &self,
+        peer: PeerId,
+        address: PeerAddress,
+        payload: Payload,
+    ) -> Option<Payload>;
+    fn verify_accepted_connection(
+        &self,
+        peer: PeerId,
+        address: PeerAddress,
+        payload: Payload,
+    ) -> Option<Payload>;
+    fn verify_confirmed_connection(
+        &self,
+        peer: PeerId,
+        address: PeerAddress,
+        payload: Payload,
+    ) -> bool;
+    fn verify_closed_connection(
+        &self,
+        peer: PeerId,
+        address: PeerAddress,
+        payload: Payload,
+    ) -> bool;
+    // the transport lost its connection to `peer` at `address`, and it no
+    // longer has a peer key for this protocol
+    fn connection_lost(&self, _peer: PeerId, _address: PeerAddress) {}
+    // traffic with `peer` at `address` is now encrypted, and it proved it
+    // holds the private half of `remote_key`; only called when the node has a
+    // keypair
+    fn secured(&self, _peer: PeerId, _address: PeerAddress, _remote_key: PublicKey) {}
+}
+
+// same as `Handler`, but the node drives the returned futures alongside
+// everything else and applies verification results once they resolve
+pub trait AsyncHandler: Send {
+    fn handle_message(
+        &self,
+        peer: PeerId,
+        address: PeerAddress,
+        payload: Payload,
+    ) -> BoxFuture<'static, ()>;
+    fn verify_requested_connection(
+        &self,
+        peer: PeerId,
+        address: PeerAddress,
+        payload: Payload,
+    ) -> BoxFuture<'static, Option<Payload>>;
+    fn verify_accepted_connection(
+        &self,
+        peer: PeerId,
+        address: PeerAddress,
+        payload: Payload,
+    ) -> BoxFuture<'static, Option<Payload>>;
+    fn verify_confirmed_connection(
+        &self,
+        peer: PeerId,
+        address: PeerAddress,
+        payload: Payload,
+    ) -> BoxFuture<'static, bool>;
+    fn verify_closed_connection(
+        &self,
+        peer: PeerId,
+        address: PeerAddress,
+        payload: Payload,
+    ) -> BoxFuture<'static, bool>;
+    fn connection_lost(&self, _peer: PeerId, _address: PeerAddress) -> BoxFuture<'static, ()> {
+        future::ready(()).boxed()
+    }
+    fn secured(
+        &self,
+        _peer: PeerId,
+        _address: PeerAddress,
+        _remote_key: PublicKey,
+    ) -> BoxFuture<'static, ()> {
+        future::ready(()).boxed()
+    }
+}
+
+// runs a synchronous handler in place, handing back already resolved futures
+pub(crate) struct SyncHandler(pub(crate) Box<dyn Handler>);
+impl AsyncHandler for SyncHandler {
+    fn handle_message(
+        &self,
+        peer: PeerId,
+        address: PeerAddress,
+        payload: Payload,
+    ) -> BoxFuture<'static, ()> {
+        self.0.handle_message(peer, address, payload);
+        future::ready(()).boxed()
+    }
+    fn verify_requested_connection(
+        &self,
+        peer: PeerId,
+        address: PeerAddress,
+        payload: Payload,
+    ) -> BoxFuture<'static, Option<Payload>> {
+        future::ready(self.0.verify_requested_connection(peer, address, payload)).boxed()
+    }
+    fn verify_accepted_connection(
+        &self,
+        peer: PeerId,
+        address: PeerAddress,
+        payload: Payload,
+    ) -> BoxFuture<'static, Option<Payload>> {
+        future::ready(self.0.verify_accepted_connection(peer, address, payload)).boxed()
+    }
+    fn verify_confirmed_connection(
+        &self,
+        peer: PeerId,
+        address: PeerAddress,
+        payload: Payload,
+    ) -> BoxFuture<'static, bool> {
+        future::ready(self.0.verify_confirmed_connection(peer, address, payload)).boxed()
+    }
+    fn verify_closed_connection(
+        &self,
+        peer: PeerId,
+        address: PeerAddress,
+        payload: Payload,
+    ) -> BoxFuture<'static, bool> {
+        future::ready(self.0.verify_closed_connection(peer, address, payload)).boxed()
+    }
+    fn connection_lost(&self, peer: PeerId, address: PeerAddress) -> BoxFuture<'static, ()> {
+        self.0.connection_lost(peer, address);
+        future::ready(()).boxed()
+    }
+    fn secured(
+        &self,
+        peer: PeerId,
+        address: PeerAddress,
+        remote_key: PublicKey,
+    ) -> BoxFuture<'static, ()> {
+        self.0.secured(peer, address, remote_key);
+        future::ready(()).boxed()
+    }
 }
 
 pub(crate) struct Protocol {
-    pub(crate) handler: Box<dyn Handler>,
+    pub(crate) handler: Box<dyn AsyncHandler>,
     pub(crate) key: ProtocolKey,
-    pub(crate) peer_keys: HashMap<PeerAddress, ProtocolKey>,
+    pub(crate) peer_keys: HashMap<PeerId, ProtocolKey>,
 }
@@ -0,0 +1,163 @@
+use super::{AsyncHandler, Protocol};
+use crate::message::{ProtocolId, ProtocolKey};
+use crate::RelayError;
+use std::collections::{HashMap, VecDeque};
+use tracing::debug;
+
+/*
+
+    Keeps the protocol table and the key -> id index in sync. Keys are
+    handed out in order starting at 1. Keys of unregistered protocols are
+    only recycled once every other key has been handed out, oldest first,
+    so a peer that hasn't caught up with a close yet is unlikely to reach
+    a different protocol through the old key.
+
+*/
+#[derive(Default)]
+pub(crate) struct Registry {
+    protocols_by_id: HashMap<ProtocolId, Protocol>,
+    ids_by_key: HashMap<ProtocolKey, ProtocolId>,
+    free_keys: VecDeque<ProtocolKey>,
+    last_key: ProtocolKey,
+}
+
+impl Registry {
+    pub(crate) fn register(
+        &mut self,
+        id: ProtocolId,
+        handler: Box<dyn AsyncHandler>,
+    ) -> Result<ProtocolKey, RelayError> {
+        // remove registered handler
+        //   if it existed, extract and reuse existing key/peer_keys
+        //   if not, create new key/peer_keys
+        let (key, peer_keys) = match self.protocols_by_id.remove(&id) {
+            Some(protocol) => (protocol.key, protocol.peer_keys),
+            None => (self.next_key()?, HashMap::new()),
+        };
+
+        // construct a new protocol and insert it into the tables
+        let protocol = Protocol {
+            handler,
+            key,
+            peer_keys,
+        };
+        debug!(protocol_id = ?id, protocol_key = key, "registered protocol");
+        self.ids_by_key.insert(key, id.clone());
+        self.protocols_by_id.insert(id, protocol);
+
+        Ok(key)
+    }
+
+    pub(crate) fn unregister(&mut self, id: &ProtocolId) -> Option<Protocol> {
+        let protocol = self.protocols_by_id.remove(id)?;
+        debug!(protocol_id = ?id, protocol_key = protocol.key, "unregistered protocol");
+        self.ids_by_key.remove(&protocol.key);
+        self.free_keys.push_back(protocol.key);
+        Some(protocol)
+    }
+
+    pub(crate) fn ids(&self) -> impl Iterator<Item = &ProtocolId> {
+        self.protocols_by_id.keys()
+    }
+
+    pub(crate) fn get(&self, id: &ProtocolId) -> Option<&Protocol> {
+        self.protocols_by_id.get(id)
+    }
+
+    pub(crate) fn get_mut(&mut self, id: &ProtocolId) -> Option<&mut Protocol> {
+        self.protocols_by_id.get_mut(id)
+    }
+
+    pub(crate) fn get_id(&self, key: ProtocolKey) -> Option<&ProtocolId> {
+        self.ids_by_key.get(&key)
+    }
+
+    fn next_key(&mut self) -> Result<ProtocolKey, RelayError> {
+        if let Some(key) = self.last_key.checked_add(1) {
+            self.last_key = key;
+            return Ok(key);
+        }
+        match self.free_keys.pop_front() {
+            Some(key) => Ok(key),
+            None => Err(RelayError::KeysExhausted),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::PeerId;
+    use crate::protocol::{Handler, SyncHandler};
+    use crate::transport::PeerAddress;
+    use crate::Payload;
+
+    struct Refuse;
+    impl Handler for Refuse {
+        fn handle_message(&self, _: PeerId, _: PeerAddress, _: Payload) {}
+        fn verify_requested_connection(
+            &self,
+            _: PeerId,
+            _: PeerAddress,
+            _: Payload,
+        ) -> Option<Payload> {
+            None
+        }
+        fn verify_accepted_connection(
+            &self,
+            _: PeerId,
+            _: PeerAddress,
+            _: Payload,
+        ) -> Option<Payload> {
+            None
+        }
+        fn verify_confirmed_connection(&self, _: PeerId, _: PeerAddress, _: Payload) -> bool {
+            false
+        }
+        fn verify_closed_connection(&self, _: PeerId, _: PeerAddress, _: Payload) -> bool {
+            false
+        }
+    }
+
+    fn register(registry: &mut Registry, id: usize) -> Result<ProtocolKey, RelayError> {
+        let handler = Box::new(SyncHandler(Box::new(Refuse)));
+        registry.register(id.to_string().into_bytes(), handler)
+    }
+
+    #[test]
+    fn keys_are_handed_out_in_order_until_they_run_out() {
+        let mut registry = Registry::default();
+        for id in 1..=usize::from(ProtocolKey::MAX) {
+            assert_eq!(register(&mut registry, id).unwrap(), id as ProtocolKey);
+        }
+        assert!(matches!(
+            register(&mut registry, 0),
+            Err(RelayError::KeysExhausted)
+        ));
+        // registering again keeps the key without needing a new one
+        assert_eq!(register(&mut registry, 7).unwrap(), 7);
+    }
+
+    #[test]
+    fn freed_keys_are_recycled_oldest_first_once_the_rest_are_used() {
+        let mut registry = Registry::default();
+        register(&mut registry, 1).unwrap();
+        register(&mut registry, 2).unwrap();
+        registry.unregister(&b"2".to_vec()).unwrap();
+        registry.unregister(&b"1".to_vec()).unwrap();
+        assert_eq!(registry.get_id(1), None);
+
+        // fresh keys come first
+        assert_eq!(register(&mut registry, 3).unwrap(), 3);
+        for id in 4..=usize::from(ProtocolKey::MAX) {
+            register(&mut registry, id).unwrap();
+        }
+        assert_eq!(register(&mut registry, 256).unwrap(), 2);
+        assert_eq!(register(&mut registry, 257).unwrap(), 1);
+        assert_eq!(registry.get_id(2), Some(&b"256".to_vec()));
+        assert!(matches!(
+            register(&mut registry, 258),
+            Err(RelayError::KeysExhausted)
+        ));
+    }
+}
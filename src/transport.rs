@@ -1,13 +1,113 @@
+pub(crate) mod connections;
+pub(crate) mod fragment;
+pub(crate) mod memory;
+pub(crate) mod reliable;
 pub(crate) mod router;
 pub(crate) mod tcp;
 pub(crate) mod udp;
+pub(crate) mod unix;
 
-use bytes::Bytes;
+use crate::RelayError;
+use bytes::{Bytes, BytesMut};
+use futures::{
+    future::{self, BoxFuture, FutureExt},
+    stream::{BoxStream, SelectAll},
+    StreamExt,
+};
 use std::hash::Hash;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::os::unix::net::SocketAddr as UnixSocketAddr;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc::{error::TrySendError, Receiver, Sender};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+pub(crate) const TCP_SCHEME: &str = "tcp";
+pub(crate) const UDP_SCHEME: &str = "udp";
+pub(crate) const UNIX_STREAM_SCHEME: &str = "unix";
+pub(crate) const UNIX_DATAGRAM_SCHEME: &str = "unixgram";
+pub(crate) const MEMORY_SCHEME: &str = "memory";
+
+pub(crate) const DEFAULT_PORT: u16 = 27850;
+pub(crate) const DEFAULT_BUFFER_SIZE: usize = 256 * 1024;
+pub(crate) const DEFAULT_MTU: usize = 512;
+pub(crate) const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+pub(crate) const DEFAULT_REASSEMBLY_PEER_LIMIT: usize = 1 << 20;
+pub(crate) const DEFAULT_REASSEMBLY_TOTAL_LIMIT: usize = 16 << 20;
+pub(crate) const DEFAULT_RETRANSMISSION_TIMEOUT: Duration = Duration::from_millis(250);
+pub(crate) const DEFAULT_MAX_ATTEMPTS: u32 = 6;
+pub(crate) const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+pub(crate) const DEFAULT_PEER_QUEUE_CAPACITY: usize = 256;
+
+// what to do with a peer whose outbound queue is full because it isn't
+// reading fast enough
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    // drop the frame that doesn't fit, and keep the connection
+    #[default]
+    Drop,
+    // give up on the connection
+    Disconnect,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Queues {
+    // frames waiting to go out through a transport, and frames or events
+    // waiting for the node to pick them up
+    pub(crate) capacity: usize,
+    // frames waiting to be written to a single connection
+    pub(crate) peer_capacity: usize,
+    pub(crate) overflow: Overflow,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Config {
+    // the tcp and udp listeners can be left out, for nodes that only talk
+    // over other transports
+    pub(crate) tcp: bool,
+    pub(crate) udp: bool,
+    pub(crate) tcp_address: SocketAddr,
+    pub(crate) udp_address: SocketAddr,
+    pub(crate) udp_buffer_size: usize,
+    pub(crate) udp_mtu: usize,
+    pub(crate) udp_reassembly: fragment::Limits,
+    pub(crate) udp_reliability: reliable::Config,
+    // unix sockets are only bound when a path is given
+    pub(crate) unix_stream_path: Option<PathBuf>,
+    pub(crate) unix_datagram_path: Option<PathBuf>,
+    pub(crate) queues: Queues,
+}
+impl Default for Config {
+    fn default() -> Self {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        Config {
+            tcp: true,
+            udp: true,
+            tcp_address: SocketAddr::new(ip, DEFAULT_PORT),
+            udp_address: SocketAddr::new(ip, DEFAULT_PORT),
+            udp_buffer_size: DEFAULT_BUFFER_SIZE,
+            udp_mtu: DEFAULT_MTU,
+            udp_reassembly: fragment::Limits {
+                timeout: DEFAULT_REASSEMBLY_TIMEOUT,
+                per_peer: DEFAULT_REASSEMBLY_PEER_LIMIT,
+                total: DEFAULT_REASSEMBLY_TOTAL_LIMIT,
+            },
+            udp_reliability: reliable::Config {
+                initial_timeout: DEFAULT_RETRANSMISSION_TIMEOUT,
+                max_attempts: DEFAULT_MAX_ATTEMPTS,
+            },
+            unix_stream_path: None,
+            unix_datagram_path: None,
+            queues: Queues {
+                capacity: DEFAULT_QUEUE_CAPACITY,
+                peer_capacity: DEFAULT_PEER_QUEUE_CAPACITY,
+                overflow: Overflow::default(),
+            },
+        }
+    }
+}
 
 #[derive(Clone, Debug, PartialEq, Hash)]
 pub enum TransportProtocol {
@@ -15,6 +115,16 @@ pub enum TransportProtocol {
     Stream,
 }
 
+// only datagram transports can lose frames, stream transports always
+// deliver reliably; unix datagram sockets can't deliver reliably and fail
+// sends that ask them to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Delivery {
+    #[default]
+    BestEffort,
+    Reliable,
+}
+
 #[derive(Clone, Debug)]
 pub enum PeerAddress {
     Unix {
@@ -25,6 +135,32 @@ pub enum PeerAddress {
         address: SocketAddr,
         protocol: TransportProtocol,
     },
+    // a `MemoryTransport` in the same process
+    Memory {
+        name: String,
+    },
+    // addresses for transports plugged in through `NodeBuilder::transport`
+    Custom {
+        scheme: String,
+        address: String,
+    },
+}
+impl PeerAddress {
+    // picks the transport that frames for this peer go through
+    pub fn scheme(&self) -> &str {
+        match self {
+            PeerAddress::Unix { protocol, .. } => match protocol {
+                TransportProtocol::Datagram => UNIX_DATAGRAM_SCHEME,
+                TransportProtocol::Stream => UNIX_STREAM_SCHEME,
+            },
+            PeerAddress::Internet { protocol, .. } => match protocol {
+                TransportProtocol::Datagram => UDP_SCHEME,
+                TransportProtocol::Stream => TCP_SCHEME,
+            },
+            PeerAddress::Memory { .. } => MEMORY_SCHEME,
+            PeerAddress::Custom { scheme, .. } => scheme,
+        }
+    }
 }
 impl Hash for PeerAddress {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
@@ -38,6 +174,11 @@ impl Hash for PeerAddress {
                 address.hash(state);
                 protocol.hash(state);
             }
+            PeerAddress::Memory { name } => name.hash(state),
+            PeerAddress::Custom { scheme, address } => {
+                scheme.hash(state);
+                address.hash(state);
+            }
         }
     }
 }
@@ -64,6 +205,19 @@ impl PartialEq for PeerAddress {
                     protocol: r_protocol,
                 },
             ) => l_address == r_address && l_protocol == r_protocol,
+            (PeerAddress::Memory { name: l_name }, PeerAddress::Memory { name: r_name }) => {
+                l_name == r_name
+            }
+            (
+                PeerAddress::Custom {
+                    scheme: l_scheme,
+                    address: l_address,
+                },
+                PeerAddress::Custom {
+                    scheme: r_scheme,
+                    address: r_address,
+                },
+            ) => l_scheme == r_scheme && l_address == r_address,
             _ => false,
         }
     }
@@ -76,13 +230,192 @@ pub struct Message {
     pub payload: Bytes,
 }
 
-pub(crate) type TransportTx = UnboundedSender<Message>;
-pub(crate) type TransportRx = UnboundedReceiverStream<Message>;
+// what transports report to the node
+#[derive(Clone, Debug)]
+pub enum TransportEvent {
+    Connected(PeerAddress),
+    Disconnected(PeerAddress),
+    Message(Message),
+}
+
+pub type TransportEvents = BoxStream<'static, TransportEvent>;
+
+/*
+
+    A transport carries frames to and from peers whose address has its
+    scheme. The node binds every transport when it's built, merges their
+    event streams, and hands each outgoing frame to the transport for the
+    peer's scheme. Frames are whole relay messages; a transport has to keep
+    them intact (framing streams, fragmenting datagrams) on its own.
+
+*/
+pub trait Transport: Send {
+    fn scheme(&self) -> &str;
+    // start listening, the returned stream ends once the transport stops
+    fn bind(&mut self) -> BoxFuture<'_, Result<TransportEvents, RelayError>>;
+    // queue up a frame for `address`, waiting for room if the transport is
+    // backed up; delivery only matters to transports that can lose frames
+    fn send(
+        &self,
+        address: PeerAddress,
+        bytes: Bytes,
+        delivery: Delivery,
+    ) -> BoxFuture<'static, Result<(), RelayError>>;
+    // same as `send`, but fails with `RelayError::QueueFull` right away
+    // rather than waiting
+    fn try_send(
+        &self,
+        address: PeerAddress,
+        bytes: Bytes,
+        delivery: Delivery,
+    ) -> Result<(), RelayError>;
+    // drop the connection to `address` without sending what's still queued
+    // up for it, transports without connections have nothing to do
+    fn disconnect(&self, address: PeerAddress);
+    // send whatever is still queued up, then stop
+    fn shutdown(&mut self) -> BoxFuture<'_, ()>;
+    // how many frames and events to queue up on their way in to the node,
+    // given before the transport is bound; transports can size their
+    // queues some other way
+    fn set_queue_capacity(&mut self, _capacity: usize) {}
+}
+
+pub(crate) type TransportRx = SelectAll<TransportEvents>;
 
-struct TransportFrame {
-    address: SocketAddr,
+struct TransportFrame<A = SocketAddr> {
+    address: A,
     bytes: Bytes,
 }
 
-type FrameTx = UnboundedSender<TransportFrame>;
-type FrameRx = UnboundedReceiver<TransportFrame>;
+type FrameTx<A = SocketAddr> = Sender<TransportFrame<A>>;
+type FrameRx<A = SocketAddr> = Receiver<TransportFrame<A>>;
+
+struct Datagram {
+    frame: TransportFrame,
+    delivery: Delivery,
+}
+
+type DatagramTx = Sender<Datagram>;
+type DatagramRx = Receiver<Datagram>;
+
+// connection events only come from stream transports
+enum Inbound<A = SocketAddr> {
+    Connected(A),
+    Frame(TransportFrame<A>),
+    Disconnected(A),
+}
+
+type InboundTx<A = SocketAddr> = Sender<Inbound<A>>;
+type InboundRx<A = SocketAddr> = Receiver<Inbound<A>>;
+
+// queue up an outgoing frame for a transport's tasks once there's room, if
+// they're running
+fn queue<T: Send + 'static>(
+    out_tx: &Option<Sender<T>>,
+    item: T,
+) -> BoxFuture<'static, Result<(), RelayError>> {
+    match out_tx.clone() {
+        Some(out_tx) => async move {
+            out_tx
+                .send(item)
+                .await
+                .map_err(|_| RelayError::TransportClosed)
+        }
+        .boxed(),
+        None => future::ready(Err(RelayError::TransportClosed)).boxed(),
+    }
+}
+
+fn try_queue<T>(
+    out_tx: &Option<Sender<T>>,
+    address: PeerAddress,
+    item: T,
+) -> Result<(), RelayError> {
+    match out_tx {
+        Some(out_tx) => match out_tx.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(RelayError::QueueFull(address)),
+            Err(TrySendError::Closed(_)) => Err(RelayError::TransportClosed),
+        },
+        None => Err(RelayError::TransportClosed),
+    }
+}
+
+// hand something we received to the node once it has room for it, giving up
+// if the node has stopped listening or the transport is shutting down
+async fn deliver<A>(
+    inbound_tx: &InboundTx<A>,
+    inbound: Inbound<A>,
+    shutdown: &CancellationToken,
+) -> bool {
+    tokio::select! {
+        biased;
+        sent = inbound_tx.send(inbound) => sent.is_ok(),
+        () = shutdown.cancelled() => false,
+    }
+}
+
+// the largest datagram udp can carry
+pub(crate) const MAX_UDP_DATAGRAM: usize = 65535;
+
+// room for a datagram of up to `max_len` bytes at the front of `buf`.
+// Datagrams are received into a block of `block_size` bytes and split off it
+// as they arrive, so a block is allocated and zeroed once rather than for
+// every datagram; a new one starts once what's left of the current one is
+// too small
+fn receive_space(buf: &mut BytesMut, max_len: usize, block_size: usize) -> &mut [u8] {
+    if buf.len() < max_len {
+        *buf = BytesMut::zeroed(block_size.max(max_len));
+    }
+    &mut buf[..max_len]
+}
+
+// turn what a transport's tasks receive into events for the node, using
+// `peer_address` to translate their addresses
+fn events<A, F>(inbound_rx: InboundRx<A>, peer_address: F) -> TransportEvents
+where
+    A: Send + 'static,
+    F: Fn(A) -> Option<PeerAddress> + Send + 'static,
+{
+    ReceiverStream::new(inbound_rx)
+        .filter_map(move |inbound| {
+            let event = match inbound {
+                Inbound::Connected(address) => peer_address(address).map(TransportEvent::Connected),
+                Inbound::Disconnected(address) => {
+                    peer_address(address).map(TransportEvent::Disconnected)
+                }
+                Inbound::Frame(TransportFrame { address, bytes }) => {
+                    peer_address(address).map(|address| {
+                        TransportEvent::Message(Message {
+                            address,
+                            payload: bytes,
+                        })
+                    })
+                }
+            };
+            future::ready(event)
+        })
+        .boxed()
+}
+
+fn internet_address(protocol: TransportProtocol) -> impl Fn(SocketAddr) -> Option<PeerAddress> {
+    move |address| {
+        Some(PeerAddress::Internet {
+            address,
+            protocol: protocol.clone(),
+        })
+    }
+}
+
+fn unix_address(protocol: TransportProtocol) -> impl Fn(PathBuf) -> Option<PeerAddress> {
+    move |path| match UnixSocketAddr::from_pathname(&path) {
+        Ok(address) => Some(PeerAddress::Unix {
+            address,
+            protocol: protocol.clone(),
+        }),
+        Err(err) => {
+            warn!(path = %path.display(), error = %err, "invalid unix peer address");
+            None
+        }
+    }
+}
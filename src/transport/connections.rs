@@ -0,0 +1,566 @@
+use super::{deliver, FrameRx, Inbound, InboundTx, Queues, TransportFrame};
+use crate::transport::Overflow;
+use bytes::Bytes;
+use futures::{
+    future::BoxFuture,
+    stream::{SplitSink, SplitStream},
+    Future, SinkExt, StreamExt,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{
+        mpsc::{self, error::TrySendError, Receiver, Sender, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+use tokio_util::{codec::LengthDelimitedCodec, sync::CancellationToken};
+use tracing::{debug, debug_span, trace, warn, Instrument};
+
+/*
+
+    The connection machinery the stream transports share. Each of them
+    accepts connections its own way and says how to dial a peer, and from
+    there every connection is handled the same, filed under whatever the
+    transport knows its peers by.
+
+    Runs until the outgoing frame channel is closed. At that point every
+    queued frame has been handed to a connection, so we close each of them
+    (flushing their sinks) before returning. Inbound streams, dials and the
+    accept loop are stopped by the shutdown token.
+
+    Connections we dial or `accept` report `Inbound::Connected` once
+    they're established and `Inbound::Disconnected` when their stream
+    ends, at which point we close our half and forget about them.
+    Connections accepted `read_only` are never sent over and report
+    neither. Connections the node gives up on are dropped right away,
+    without waiting for their queue to drain.
+
+    Frames for a connection wait in a queue of their own, so one slow peer
+    can't hold up the rest. Once that queue is full the overflow policy
+    decides whether we drop the frame or the connection.
+
+*/
+
+// what a stream transport knows its peers by
+pub(super) trait Address: Clone + Eq + Hash + Send + Sync + 'static {
+    // how the peer shows up in logs
+    fn peer(&self) -> impl fmt::Display + '_;
+}
+impl Address for SocketAddr {
+    fn peer(&self) -> impl fmt::Display + '_ {
+        self
+    }
+}
+impl Address for PathBuf {
+    fn peer(&self) -> impl fmt::Display + '_ {
+        self.display()
+    }
+}
+
+pub(super) type Framed<S> = tokio_util::codec::Framed<S, LengthDelimitedCodec>;
+
+pub(super) fn framed<S: AsyncRead + AsyncWrite>(stream: S) -> Framed<S> {
+    Framed::new(stream, LengthDelimitedCodec::new())
+}
+
+// a connection to a peer, ready to carry frames once it resolves
+pub(super) type Dial<S> = BoxFuture<'static, io::Result<Framed<S>>>;
+
+enum ConnectionMessage<A> {
+    // a connection frames for `address` go out on
+    New { address: A, connection: Connection },
+    // a connection we only read from, from whoever claims to be `address`
+    ReadOnly { address: A, connection: Connection },
+    Send { address: A, bytes: Bytes },
+}
+
+type ConnMsgTx<A> = Sender<ConnectionMessage<A>>;
+type ConnMessageRx<A> = Receiver<ConnectionMessage<A>>;
+
+// sent when a connection's stream ends, so its map entry can be dropped, or
+// without an id to drop whatever connections we have right away
+struct Closed<A> {
+    address: A,
+    id: Option<ConnectionId>,
+    // told whether the connection was still the one we had for its address
+    current_tx: Option<oneshot::Sender<bool>>,
+}
+
+type ClosedTx<A> = UnboundedSender<Closed<A>>;
+type ClosedRx<A> = UnboundedReceiver<Closed<A>>;
+
+// tells apart connections to the same address, so a late close for an old
+// connection doesn't take down its replacement
+type ConnectionId = u64;
+
+fn next_connection_id() -> ConnectionId {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+type BytesTx = Sender<Bytes>;
+type BytesRx = Receiver<Bytes>;
+
+struct Connection {
+    id: ConnectionId,
+    bytes_tx: BytesTx,
+    // stops the connection's stream, a child of the transport's shutdown token
+    cancel: CancellationToken,
+    sink_task: JoinHandle<()>,
+}
+impl Connection {
+    // drop the connection without waiting for its queue to drain
+    fn disconnect(self) {
+        self.cancel.cancel();
+        self.sink_task.abort();
+    }
+
+    // let the sink drain whatever is still queued up, then wait for it to close
+    async fn close(self) {
+        let Connection {
+            bytes_tx,
+            sink_task,
+            ..
+        } = self;
+        drop(bytes_tx);
+        let _ = sink_task.await;
+    }
+}
+
+// hands the connections a transport accepted over to the rest of them
+#[derive(Clone)]
+pub(super) struct Acceptor<A> {
+    inbound_tx: InboundTx<A>,
+    conn_msg_tx: ConnMsgTx<A>,
+    closed_tx: ClosedTx<A>,
+    peer_capacity: usize,
+    shutdown: CancellationToken,
+}
+impl<A: Address> Acceptor<A> {
+    // a connection from `address` that frames for it go out on
+    pub(super) async fn accept<S>(&self, address: A, framed: Framed<S>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let span = debug_span!("connection", peer = %address.peer(), direction = "inbound");
+        debug!(parent: &span, "accepted");
+
+        let (sink, stream) = framed.split();
+        let (id, connection, cancel) = self.connection(sink, self.peer_capacity, span.clone());
+        self.register(ConnectionMessage::New {
+            address: address.clone(),
+            connection,
+        })
+        .await;
+
+        deliver(
+            &self.inbound_tx,
+            Inbound::Connected(address.clone()),
+            &cancel,
+        )
+        .await;
+        let inbound_tx = self.inbound_tx.clone();
+        let closed_tx = self.closed_tx.clone();
+        let stream_task = handle_stream(stream, address, id, inbound_tx, closed_tx, cancel);
+        tokio::spawn(stream_task.instrument(span));
+    }
+
+    // a connection we never send over, filing what it carries under
+    // `address`; runs until its stream ends
+    pub(super) async fn read_only<S>(&self, address: A, framed: Framed<S>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let span = debug_span!("connection", peer = %address.peer(), direction = "inbound");
+        debug!(parent: &span, "accepted");
+
+        // the sink is only kept to close it
+        let (sink, stream) = framed.split();
+        let (id, connection, cancel) = self.connection(sink, 1, span.clone());
+        self.register(ConnectionMessage::ReadOnly {
+            address: address.clone(),
+            connection,
+        })
+        .await;
+
+        async move {
+            receive_frames(stream, &address, &self.inbound_tx, cancel).await;
+            debug!("disconnected");
+            let _ = self.closed_tx.send(Closed {
+                address,
+                id: Some(id),
+                current_tx: None,
+            });
+        }
+        .instrument(span)
+        .await
+    }
+
+    // resolves once the transport is shutting down
+    pub(super) async fn cancelled(&self) {
+        self.shutdown.cancelled().await
+    }
+
+    fn connection<S>(
+        &self,
+        sink: SplitSink<Framed<S>, Bytes>,
+        capacity: usize,
+        span: tracing::Span,
+    ) -> (ConnectionId, Connection, CancellationToken)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let id = next_connection_id();
+        let (bytes_tx, bytes_rx) = mpsc::channel(capacity);
+        let sink_task = tokio::spawn(handle_sink(sink, bytes_rx).instrument(span));
+        let cancel = self.shutdown.child_token();
+        let connection = Connection {
+            id,
+            bytes_tx,
+            cancel: cancel.clone(),
+            sink_task,
+        };
+        (id, connection, cancel)
+    }
+
+    async fn register(&self, message: ConnectionMessage<A>) {
+        if self.conn_msg_tx.send(message).await.is_err() {
+            warn!("couldn't register connection, receiver is closed");
+        }
+    }
+}
+
+// runs `accept` alongside the connections it hands over and the ones
+// `dial` opens, see above
+pub(super) async fn run<A, S, F>(
+    accept: impl FnOnce(Acceptor<A>) -> F,
+    dial: impl Fn(&A) -> Dial<S>,
+    queues: Queues,
+    inbound_tx: InboundTx<A>,
+    out_frame_rx: FrameRx<A>,
+    disconnect_rx: UnboundedReceiver<A>,
+    shutdown: CancellationToken,
+) where
+    A: Address,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Future<Output = ()>,
+{
+    debug!("listening");
+    let (conn_msg_tx, conn_msg_rx) = mpsc::channel(queues.capacity);
+    let (closed_tx, closed_rx) = mpsc::unbounded_channel();
+    let acceptor = Acceptor {
+        inbound_tx: inbound_tx.clone(),
+        conn_msg_tx: conn_msg_tx.clone(),
+        closed_tx: closed_tx.clone(),
+        peer_capacity: queues.peer_capacity,
+        shutdown: shutdown.clone(),
+    };
+    let accepting = async {
+        tokio::select! {
+            () = accept(acceptor) => {}
+            () = shutdown.cancelled() => {}
+        }
+    };
+    tokio::join!(
+        accepting,
+        relay_disconnects(disconnect_rx, closed_tx.clone()),
+        process_connection_messages(
+            conn_msg_rx,
+            closed_tx,
+            closed_rx,
+            dial,
+            inbound_tx,
+            queues,
+            shutdown.clone()
+        ),
+        relay_outgoing_bytes(out_frame_rx, conn_msg_tx),
+    );
+    debug!("stopped");
+}
+
+async fn handle_stream<A: Address, S>(
+    stream: SplitStream<Framed<S>>,
+    address: A,
+    id: ConnectionId,
+    inbound_tx: InboundTx<A>,
+    closed_tx: ClosedTx<A>,
+    cancel: CancellationToken,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    receive_frames(stream, &address, &inbound_tx, cancel).await;
+
+    debug!("disconnected");
+    if forget(address.clone(), id, &closed_tx).await {
+        // nothing waits on this task, so it can wait for the node to catch up
+        let _ = inbound_tx.send(Inbound::Disconnected(address)).await;
+    }
+}
+
+// forget a connection that ended (or never got going), true unless another
+// connection to the same peer has taken its place, and the peer isn't gone
+async fn forget<A>(address: A, id: ConnectionId, closed_tx: &ClosedTx<A>) -> bool {
+    let (current_tx, current_rx) = oneshot::channel();
+    let _ = closed_tx.send(Closed {
+        address,
+        id: Some(id),
+        current_tx: Some(current_tx),
+    });
+    current_rx.await.unwrap_or(false)
+}
+
+async fn receive_frames<A: Address, S>(
+    mut stream: SplitStream<Framed<S>>,
+    address: &A,
+    inbound_tx: &InboundTx<A>,
+    cancel: CancellationToken,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    loop {
+        let frame = tokio::select! {
+            frame = stream.next() => match frame {
+                Some(frame) => frame,
+                None => return,
+            },
+            () = cancel.cancelled() => return,
+        };
+        match frame {
+            Ok(bytes) => {
+                let len = bytes.len();
+                let msg = TransportFrame {
+                    address: address.clone(),
+                    bytes: bytes.freeze(),
+                };
+                // stop reading until the node catches up
+                if !deliver(inbound_tx, Inbound::Frame(msg), &cancel).await {
+                    return;
+                }
+                trace!(bytes = len, "received");
+            }
+            Err(err) => warn!(error = %err, "receive failed"),
+        }
+    }
+}
+
+async fn handle_sink<S>(mut sink: SplitSink<Framed<S>, Bytes>, mut bytes_rx: BytesRx)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    while let Some(bytes) = bytes_rx.recv().await {
+        let count = bytes.len();
+        match sink.send(bytes).await {
+            Ok(()) => trace!(bytes = count, "sent"),
+            Err(err) => warn!(error = %err, "send failed"),
+        }
+    }
+
+    // no more bytes are coming, flush and close our half of the connection
+    match sink.close().await {
+        Ok(()) => debug!("closed"),
+        Err(err) => debug!(error = %err, "close failed"),
+    }
+}
+
+async fn connect<A: Address, S>(
+    address: A,
+    id: ConnectionId,
+    dial: Dial<S>,
+    inbound_tx: InboundTx<A>,
+    closed_tx: ClosedTx<A>,
+    bytes_rx: BytesRx,
+    cancel: CancellationToken,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let framed = tokio::select! {
+        framed = dial => framed,
+        () = cancel.cancelled() => return,
+    };
+    match framed {
+        Ok(framed) => {
+            debug!("connected");
+            deliver(&inbound_tx, Inbound::Connected(address.clone()), &cancel).await;
+            let (sink, stream) = framed.split();
+            let stream_task = handle_stream(stream, address, id, inbound_tx, closed_tx, cancel);
+            tokio::spawn(stream_task.in_current_span());
+            handle_sink(sink, bytes_rx).await;
+        }
+        Err(err) => {
+            // whatever was queued up for the connection is lost, let the
+            // node know the peer is unreachable
+            warn!(error = %err, "couldn't connect");
+            if forget(address.clone(), id, &closed_tx).await {
+                deliver(&inbound_tx, Inbound::Disconnected(address), &cancel).await;
+            }
+        }
+    }
+}
+
+async fn process_connection_messages<A: Address, S>(
+    mut conn_msg_rx: ConnMessageRx<A>,
+    closed_tx: ClosedTx<A>,
+    mut closed_rx: ClosedRx<A>,
+    dial: impl Fn(&A) -> Dial<S>,
+    inbound_tx: InboundTx<A>,
+    queues: Queues,
+    shutdown: CancellationToken,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // the connections frames go out on, by the address they reach
+    let mut map: HashMap<A, Connection> = HashMap::new();
+    // the connections we only read from, with the address their peer claims
+    let mut read_only: HashMap<ConnectionId, (A, Connection)> = HashMap::new();
+    loop {
+        let message = tokio::select! {
+            // forget closed connections first, so frames sent after a
+            // failed dial go out on a new one
+            biased;
+            Some(Closed { address, id, current_tx }) = closed_rx.recv() => {
+                match id {
+                    // dropping the connection lets its sink flush and close
+                    Some(id) => {
+                        let replaced = map.get(&address).is_some_and(|connection| connection.id != id);
+                        if !replaced {
+                            map.remove(&address);
+                        }
+                        read_only.remove(&id);
+                        if let Some(current_tx) = current_tx {
+                            let _ = current_tx.send(!replaced);
+                        }
+                    }
+                    None => {
+                        if let Some(connection) = map.remove(&address) {
+                            debug!(peer = %address.peer(), "disconnecting");
+                            connection.disconnect();
+                        }
+                        let claimed: Vec<_> = read_only
+                            .iter()
+                            .filter(|(_, (claim, _))| *claim == address)
+                            .map(|(id, _)| *id)
+                            .collect();
+                        for id in claimed {
+                            if let Some((_, connection)) = read_only.remove(&id) {
+                                connection.disconnect();
+                            }
+                        }
+                    }
+                }
+                continue;
+            }
+            message = conn_msg_rx.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+        };
+        match message {
+            ConnectionMessage::New {
+                address,
+                connection,
+            } => {
+                map.insert(address, connection);
+            }
+            ConnectionMessage::ReadOnly {
+                address,
+                connection,
+            } => {
+                read_only.insert(connection.id, (address, connection));
+            }
+            ConnectionMessage::Send { address, bytes } => {
+                // a connection whose dial failed, or that ended, may not
+                // have been forgotten yet
+                if map.get(&address).is_some_and(|c| c.bytes_tx.is_closed()) {
+                    map.remove(&address);
+                }
+                // dial out if we don't have a connection yet; bytes queue up
+                // in the channel until the connection is established
+                let connection = map.entry(address.clone()).or_insert_with(|| {
+                    let id = next_connection_id();
+                    let (bytes_tx, bytes_rx) = mpsc::channel::<Bytes>(queues.peer_capacity);
+                    let inbound_tx = inbound_tx.clone();
+                    let closed_tx = closed_tx.clone();
+                    let cancel = shutdown.child_token();
+                    let span =
+                        debug_span!("connection", peer = %address.peer(), direction = "outbound");
+                    let sink_task = connect(
+                        address.clone(),
+                        id,
+                        dial(&address),
+                        inbound_tx,
+                        closed_tx,
+                        bytes_rx,
+                        cancel.clone(),
+                    );
+                    let sink_task = tokio::spawn(sink_task.instrument(span));
+                    Connection {
+                        id,
+                        bytes_tx,
+                        cancel,
+                        sink_task,
+                    }
+                });
+                // never wait on a single peer here, that would hold up every
+                // other connection
+                let peer = address.peer();
+                match connection.bytes_tx.try_send(bytes) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => match queues.overflow {
+                        Overflow::Drop => warn!(%peer, "outbound queue is full, dropping frame"),
+                        Overflow::Disconnect => {
+                            warn!(%peer, "outbound queue is full, disconnecting");
+                            if let Some(connection) = map.remove(&address) {
+                                connection.disconnect();
+                            }
+                        }
+                    },
+                    Err(TrySendError::Closed(_)) => {
+                        warn!(%peer, "byte receiver is closed, dropping connection");
+                        map.remove(&address);
+                    }
+                }
+            }
+        }
+    }
+
+    // nothing else will be sent, close every connection; a failed dial
+    // may be waiting to hear whether it was forgotten, dropping the queue
+    // tells it no
+    drop(closed_rx);
+    let read_only = read_only.into_values().map(|(_, connection)| connection);
+    futures::future::join_all(map.into_values().chain(read_only).map(Connection::close)).await;
+}
+
+async fn relay_outgoing_bytes<A>(mut out_frame_rx: FrameRx<A>, conn_msg_tx: ConnMsgTx<A>) {
+    while let Some(message) = out_frame_rx.recv().await {
+        let TransportFrame { address, bytes } = message;
+        if conn_msg_tx
+            .send(ConnectionMessage::Send { address, bytes })
+            .await
+            .is_err()
+        {
+            warn!("connection message receiver is closed");
+            return;
+        };
+    }
+}
+
+async fn relay_disconnects<A>(mut disconnect_rx: UnboundedReceiver<A>, closed_tx: ClosedTx<A>) {
+    while let Some(address) = disconnect_rx.recv().await {
+        let _ = closed_tx.send(Closed {
+            address,
+            id: None,
+            current_tx: None,
+        });
+    }
+}
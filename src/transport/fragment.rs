@@ -0,0 +1,401 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    mem,
+    time::{Duration, Instant},
+};
+
+/*
+
+    Every datagram starts with a fragment header
+
+        message id     u32
+        fragment index u16
+        fragment count u16
+
+    followed by that fragment's slice of the frame. Frames that fit in a
+    single datagram still carry the header, with a count of one, and skip
+    reassembly entirely on the receiving end.
+
+    A partial frame is charged against the limits for the bytes it holds
+    and for its bookkeeping, a slot per fragment it announced, so a sender
+    can't tie up memory with frames of many tiny (or no) fragments. Counts
+    are capped at what a frame of `per_peer` bytes needs at our mtu. Room
+    is made by dropping the oldest partial frames, but never the one a
+    fragment belongs to, so a frame that is about to complete isn't lost.
+
+*/
+
+pub(crate) const HEADER_SIZE: usize = 8;
+
+pub(crate) type FragmentId = u32;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Limits {
+    pub(crate) timeout: Duration,
+    // bytes of partial frames kept for a single sender
+    pub(crate) per_peer: usize,
+    // bytes of partial frames kept across all senders
+    pub(crate) total: usize,
+}
+
+// splits `bytes` into datagrams no larger than `mtu`, returns None if the
+// frame would need more fragments than the header can count
+pub(crate) fn fragment(id: FragmentId, bytes: &Bytes, mtu: usize) -> Option<Vec<Bytes>> {
+    let chunk_size = mtu.saturating_sub(HEADER_SIZE).max(1);
+    let count = bytes.len().div_ceil(chunk_size).max(1);
+    let count = u16::try_from(count).ok()?;
+
+    let mut fragments = Vec::with_capacity(count as usize);
+    for index in 0..count {
+        let start = index as usize * chunk_size;
+        let end = bytes.len().min(start + chunk_size);
+        let mut datagram = BytesMut::with_capacity(HEADER_SIZE + end - start);
+        datagram.put_u32(id);
+        datagram.put_u16(index);
+        datagram.put_u16(count);
+        datagram.put_slice(&bytes[start..end]);
+        fragments.push(datagram.freeze());
+    }
+    Some(fragments)
+}
+
+struct Partial {
+    fragments: Vec<Option<Bytes>>,
+    missing: usize,
+    // bytes held plus `overhead`, what counts against the limits
+    size: usize,
+    started: Instant,
+}
+impl Partial {
+    fn new(count: usize) -> Partial {
+        Partial {
+            fragments: vec![None; count],
+            missing: count,
+            size: overhead(count),
+            started: Instant::now(),
+        }
+    }
+}
+
+// what a partial frame of `count` fragments costs before holding any bytes
+fn overhead(count: usize) -> usize {
+    mem::size_of::<Partial>() + count * mem::size_of::<Option<Bytes>>()
+}
+
+#[derive(Default)]
+struct Peer {
+    partials: HashMap<FragmentId, Partial>,
+    size: usize,
+}
+impl Peer {
+    fn remove(&mut self, id: FragmentId) -> Option<Partial> {
+        let partial = self.partials.remove(&id)?;
+        self.size -= partial.size;
+        Some(partial)
+    }
+
+    fn oldest(&self, except: Option<FragmentId>) -> Option<FragmentId> {
+        self.partials
+            .iter()
+            .filter(|(id, _)| Some(**id) != except)
+            .min_by_key(|(_, partial)| partial.started)
+            .map(|(id, _)| *id)
+    }
+}
+
+pub(crate) struct Reassembler<A> {
+    limits: Limits,
+    max_count: usize,
+    peers: HashMap<A, Peer>,
+    size: usize,
+}
+
+impl<A: Clone + Eq + Hash> Reassembler<A> {
+    pub(crate) fn new(limits: Limits, mtu: usize) -> Reassembler<A> {
+        let chunk_size = mtu.saturating_sub(HEADER_SIZE).max(1);
+        let max_count = limits.per_peer.div_ceil(chunk_size).max(1);
+        Reassembler {
+            limits,
+            max_count,
+            peers: HashMap::new(),
+            size: 0,
+        }
+    }
+
+    // returns the whole frame once its last fragment arrives; malformed
+    // datagrams and fragments that don't fit under the limits are dropped
+    pub(crate) fn receive(&mut self, address: &A, mut datagram: Bytes) -> Option<Bytes> {
+        if datagram.len() < HEADER_SIZE {
+            return None;
+        }
+        let id = datagram.get_u32();
+        let index = datagram.get_u16() as usize;
+        let count = datagram.get_u16() as usize;
+        if index >= count {
+            return None;
+        }
+        if count == 1 {
+            return Some(datagram);
+        }
+
+        let len = datagram.len();
+        if len == 0 || count > self.max_count {
+            return None;
+        }
+
+        // duplicates are dropped before making room, so they can't push
+        // out anything else
+        let existing = self
+            .peers
+            .get(address)
+            .and_then(|peer| peer.partials.get(&id));
+        let started = match existing {
+            Some(partial) if partial.fragments.len() == count => {
+                if partial.fragments[index].is_some() {
+                    return None;
+                }
+                true
+            }
+            // a new frame reusing the id of one we never finished
+            Some(_) => {
+                self.discard(address, id);
+                false
+            }
+            None => false,
+        };
+        let charge = if started { len } else { len + overhead(count) };
+        if charge > self.limits.per_peer || charge > self.limits.total {
+            return None;
+        }
+        // the frame this continues is never what makes room for it
+        if !self.make_room(address, started.then_some(id), charge) {
+            // the rest of it could never fit
+            self.discard(address, id);
+            return None;
+        }
+
+        let peer = self.peers.entry(address.clone()).or_default();
+        let partial = peer
+            .partials
+            .entry(id)
+            .or_insert_with(|| Partial::new(count));
+        if !started {
+            peer.size += partial.size;
+            self.size += partial.size;
+        }
+        partial.fragments[index] = Some(datagram);
+        partial.missing -= 1;
+        partial.size += len;
+        peer.size += len;
+        self.size += len;
+        if partial.missing > 0 {
+            return None;
+        }
+
+        let partial = peer.remove(id)?;
+        self.size -= partial.size;
+        if peer.partials.is_empty() {
+            self.peers.remove(address);
+        }
+        let len = partial.fragments.iter().flatten().map(Bytes::len).sum();
+        let mut frame = BytesMut::with_capacity(len);
+        for fragment in partial.fragments.into_iter().flatten() {
+            frame.put(fragment);
+        }
+        Some(frame.freeze())
+    }
+
+    // drop partial frames that have been waiting longer than the timeout
+    pub(crate) fn expire(&mut self) {
+        let timeout = self.limits.timeout;
+        let mut expired = 0;
+        self.peers.retain(|_, peer| {
+            let stale: Vec<FragmentId> = peer
+                .partials
+                .iter()
+                .filter(|(_, partial)| partial.started.elapsed() >= timeout)
+                .map(|(id, _)| *id)
+                .collect();
+            for id in stale {
+                if let Some(partial) = peer.remove(id) {
+                    expired += partial.size;
+                }
+            }
+            !peer.partials.is_empty()
+        });
+        self.size -= expired;
+    }
+
+    fn discard(&mut self, address: &A, id: FragmentId) {
+        if let Some(peer) = self.peers.get_mut(address) {
+            if let Some(partial) = peer.remove(id) {
+                self.size -= partial.size;
+            }
+            if peer.partials.is_empty() {
+                self.peers.remove(address);
+            }
+        }
+    }
+
+    // evict the oldest partial frames other than `address`'s `keep`, first
+    // from `address` and then from anyone, until `len` more bytes fit under
+    // both limits; false if they can't be made to
+    fn make_room(&mut self, address: &A, keep: Option<FragmentId>, len: usize) -> bool {
+        while let Some(peer) = self.peers.get_mut(address) {
+            if peer.size + len <= self.limits.per_peer {
+                break;
+            }
+            let evicted = peer.oldest(keep).and_then(|id| peer.remove(id));
+            match evicted {
+                Some(partial) => self.size -= partial.size,
+                None => return false,
+            }
+        }
+
+        while self.size + len > self.limits.total {
+            let oldest = self
+                .peers
+                .iter()
+                .filter_map(|(peer_address, peer)| {
+                    let except = if peer_address == address { keep } else { None };
+                    let id = peer.oldest(except)?;
+                    Some((peer.partials[&id].started, peer_address.clone(), id))
+                })
+                .min_by_key(|(started, _, _)| *started);
+            match oldest {
+                Some((_, address, id)) => self.discard(&address, id),
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MTU: usize = HEADER_SIZE + 4;
+
+    fn limits(per_peer: usize, total: usize) -> Limits {
+        Limits {
+            timeout: Duration::from_secs(60),
+            per_peer,
+            total,
+        }
+    }
+
+    fn datagram(id: FragmentId, index: u16, count: u16, bytes: &[u8]) -> Bytes {
+        let mut datagram = BytesMut::new();
+        datagram.put_u32(id);
+        datagram.put_u16(index);
+        datagram.put_u16(count);
+        datagram.put_slice(bytes);
+        datagram.freeze()
+    }
+
+    #[test]
+    fn fragments_fit_the_mtu_and_reassemble_out_of_order() {
+        let frame = Bytes::from_static(b"a frame of some length");
+        let fragments = fragment(7, &frame, MTU).unwrap();
+        assert_eq!(fragments.len(), frame.len().div_ceil(MTU - HEADER_SIZE));
+        assert!(fragments.iter().all(|fragment| fragment.len() <= MTU));
+
+        let mut reassembler = Reassembler::new(limits(1 << 20, 1 << 20), MTU);
+        let (last, rest) = fragments.split_last().unwrap();
+        for fragment in rest.iter().rev() {
+            assert_eq!(reassembler.receive(&1, fragment.clone()), None);
+        }
+        assert_eq!(reassembler.receive(&1, last.clone()), Some(frame));
+        assert!(reassembler.peers.is_empty());
+        assert_eq!(reassembler.size, 0);
+    }
+
+    #[test]
+    fn single_fragments_skip_reassembly() {
+        let mut reassembler = Reassembler::new(limits(0, 0), MTU);
+        let received = reassembler.receive(&1, datagram(1, 0, 1, b"whole"));
+        assert_eq!(received, Some(Bytes::from_static(b"whole")));
+    }
+
+    #[test]
+    fn malformed_fragments_are_dropped() {
+        let mut reassembler = Reassembler::new(limits(64, 64), MTU);
+        assert_eq!(reassembler.receive(&1, Bytes::from_static(b"short")), None);
+        assert_eq!(reassembler.receive(&1, datagram(1, 2, 2, b"ab")), None);
+        assert_eq!(reassembler.receive(&1, datagram(1, 0, 2, b"")), None);
+        // more fragments than `per_peer` bytes could ever need
+        assert_eq!(reassembler.receive(&1, datagram(1, 0, 17, b"ab")), None);
+        assert!(reassembler.peers.is_empty());
+    }
+
+    #[test]
+    fn duplicates_are_dropped_without_being_charged() {
+        let mut reassembler = Reassembler::new(limits(1 << 20, 1 << 20), MTU);
+        assert_eq!(reassembler.receive(&1, datagram(1, 0, 2, b"ab")), None);
+        let size = reassembler.size;
+        assert_eq!(reassembler.receive(&1, datagram(1, 0, 2, b"xy")), None);
+        assert_eq!(reassembler.size, size);
+        let received = reassembler.receive(&1, datagram(1, 1, 2, b"cd"));
+        assert_eq!(received, Some(Bytes::from_static(b"abcd")));
+    }
+
+    #[test]
+    fn reused_ids_with_a_new_count_start_over() {
+        let mut reassembler = Reassembler::new(limits(1 << 20, 1 << 20), MTU);
+        assert_eq!(reassembler.receive(&1, datagram(1, 0, 3, b"ab")), None);
+        assert_eq!(reassembler.receive(&1, datagram(1, 0, 2, b"cd")), None);
+        let received = reassembler.receive(&1, datagram(1, 1, 2, b"ef"));
+        assert_eq!(received, Some(Bytes::from_static(b"cdef")));
+        assert_eq!(reassembler.size, 0);
+    }
+
+    #[test]
+    fn a_full_peer_loses_its_oldest_frame() {
+        let per_peer = 2 * (overhead(2) + 2);
+        let mut reassembler = Reassembler::new(limits(per_peer, 1 << 20), MTU);
+        assert_eq!(reassembler.receive(&1, datagram(1, 0, 2, b"ab")), None);
+        assert_eq!(reassembler.receive(&1, datagram(2, 0, 2, b"cd")), None);
+        // a third frame pushes out the first
+        assert_eq!(reassembler.receive(&1, datagram(3, 0, 2, b"ef")), None);
+        assert_eq!(reassembler.receive(&1, datagram(1, 1, 2, b"gh")), None);
+        assert!(reassembler.peers[&1].size <= per_peer);
+        let received = reassembler.receive(&1, datagram(3, 1, 2, b"ij"));
+        assert_eq!(received, Some(Bytes::from_static(b"efij")));
+    }
+
+    #[test]
+    fn the_total_limit_evicts_across_peers() {
+        let total = 2 * (overhead(2) + 2);
+        let mut reassembler = Reassembler::new(limits(1 << 20, total), MTU);
+        assert_eq!(reassembler.receive(&1, datagram(1, 0, 2, b"ab")), None);
+        assert_eq!(reassembler.receive(&2, datagram(1, 0, 2, b"cd")), None);
+        assert_eq!(reassembler.receive(&3, datagram(1, 0, 2, b"ef")), None);
+        assert!(!reassembler.peers.contains_key(&1));
+        assert!(reassembler.size <= total);
+        let received = reassembler.receive(&2, datagram(1, 1, 2, b"gh"));
+        assert_eq!(received, Some(Bytes::from_static(b"cdgh")));
+    }
+
+    #[test]
+    fn frames_that_can_never_fit_are_dropped() {
+        let mut reassembler = Reassembler::new(limits(overhead(2) + 1, 1 << 20), MTU);
+        assert_eq!(reassembler.receive(&1, datagram(1, 0, 2, b"ab")), None);
+        assert!(reassembler.peers.is_empty());
+        assert_eq!(reassembler.size, 0);
+    }
+
+    #[test]
+    fn stale_frames_expire() {
+        let mut limits = limits(1 << 20, 1 << 20);
+        limits.timeout = Duration::ZERO;
+        let mut reassembler = Reassembler::new(limits, MTU);
+        assert_eq!(reassembler.receive(&1, datagram(1, 0, 2, b"ab")), None);
+        reassembler.expire();
+        assert!(reassembler.peers.is_empty());
+        assert_eq!(reassembler.size, 0);
+        assert_eq!(reassembler.receive(&1, datagram(1, 1, 2, b"cd")), None);
+    }
+}
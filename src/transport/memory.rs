@@ -0,0 +1,230 @@
+use super::{
+    Delivery, Message, PeerAddress, Transport, TransportEvent, TransportEvents,
+    DEFAULT_QUEUE_CAPACITY,
+};
+use crate::RelayError;
+use bytes::Bytes;
+use futures::{
+    future::{self, BoxFuture, FutureExt},
+    StreamExt,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::{Arc, Mutex, MutexGuard},
+};
+use tokio::sync::mpsc::{self, error::TrySendError, Sender};
+use tokio_stream::wrappers::ReceiverStream;
+
+/*
+
+    Connects nodes running in the same process, mostly so they can be
+    tested without going over the network (build them `without_tcp` and
+    `without_udp` so they don't bind any sockets). Every transport on a
+    network is known by its name, and peers reach it at
+    `PeerAddress::Memory { name }`.
+    Frames are handed straight to the receiving node's event queue, so
+    they arrive in order and are never lost; once that queue is full,
+    senders wait for room or get `RelayError::QueueFull`. Two transports
+    count as connected from the first frame either sends the other until
+    one of them shuts down or disconnects the other. Those events are
+    never dropped, when a queue is full they wait for room behind the
+    frames already waiting.
+
+*/
+
+#[derive(Default)]
+struct Network {
+    endpoints: HashMap<String, Sender<TransportEvent>>,
+    links: HashMap<String, HashSet<String>>,
+}
+impl Network {
+    fn notify(&self, name: &str, event: TransportEvent) {
+        let events_tx = match self.endpoints.get(name) {
+            Some(events_tx) => events_tx,
+            None => return,
+        };
+        if let Err(TrySendError::Full(event)) = events_tx.try_send(event) {
+            let events_tx = events_tx.clone();
+            tokio::spawn(async move {
+                let _ = events_tx.send(event).await;
+            });
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    network: Arc<Mutex<Network>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> MemoryNetwork {
+        MemoryNetwork::default()
+    }
+
+    // a transport reachable at `PeerAddress::Memory { name }` once its node
+    // is built
+    pub fn transport(&self, name: impl Into<String>) -> MemoryTransport {
+        MemoryTransport {
+            name: name.into(),
+            network: self.clone(),
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Network> {
+        // nothing panics while holding the lock, but don't make that fatal
+        self.network.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+pub struct MemoryTransport {
+    name: String,
+    network: MemoryNetwork,
+    queue_capacity: usize,
+}
+
+impl MemoryTransport {
+    pub fn address(&self) -> PeerAddress {
+        PeerAddress::Memory {
+            name: self.name.clone(),
+        }
+    }
+
+    // the event queue of the node at `address`, linking us up with it the
+    // first time
+    fn link(&self, address: &PeerAddress) -> Result<Sender<TransportEvent>, RelayError> {
+        let name = match address {
+            PeerAddress::Memory { name } => name,
+            _ => return Err(RelayError::UnsupportedAddress(address.clone())),
+        };
+
+        let mut network = self.network.lock();
+        if !network.endpoints.contains_key(&self.name) {
+            return Err(RelayError::TransportClosed);
+        }
+        let events_tx = match network.endpoints.get(name) {
+            Some(events_tx) => events_tx.clone(),
+            None => return Err(RelayError::NotConnected(address.clone())),
+        };
+
+        let linked = network
+            .links
+            .entry(self.name.clone())
+            .or_default()
+            .insert(name.clone());
+        if linked {
+            network
+                .links
+                .entry(name.clone())
+                .or_default()
+                .insert(self.name.clone());
+            network.notify(&self.name, TransportEvent::Connected(address.clone()));
+            network.notify(name, TransportEvent::Connected(self.address()));
+        }
+        Ok(events_tx)
+    }
+
+    fn message(&self, bytes: Bytes) -> TransportEvent {
+        TransportEvent::Message(Message {
+            address: self.address(),
+            payload: bytes,
+        })
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn scheme(&self) -> &str {
+        super::MEMORY_SCHEME
+    }
+
+    fn bind(&mut self) -> BoxFuture<'_, Result<TransportEvents, RelayError>> {
+        let (events_tx, events_rx) = mpsc::channel(self.queue_capacity);
+        let mut network = self.network.lock();
+        let bound = if network.endpoints.contains_key(&self.name) {
+            Err(RelayError::Io(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("memory transport {:?} is already bound", self.name),
+            )))
+        } else {
+            network.endpoints.insert(self.name.clone(), events_tx);
+            Ok(ReceiverStream::new(events_rx).boxed())
+        };
+        future::ready(bound).boxed()
+    }
+
+    fn send(
+        &self,
+        address: PeerAddress,
+        bytes: Bytes,
+        _delivery: Delivery,
+    ) -> BoxFuture<'static, Result<(), RelayError>> {
+        let events_tx = match self.link(&address) {
+            Ok(events_tx) => events_tx,
+            Err(err) => return future::ready(Err(err)).boxed(),
+        };
+        let message = self.message(bytes);
+        async move {
+            match events_tx.send(message).await {
+                Ok(()) => Ok(()),
+                Err(_) => Err(RelayError::NotConnected(address)),
+            }
+        }
+        .boxed()
+    }
+
+    fn try_send(
+        &self,
+        address: PeerAddress,
+        bytes: Bytes,
+        _delivery: Delivery,
+    ) -> Result<(), RelayError> {
+        let events_tx = self.link(&address)?;
+        match events_tx.try_send(self.message(bytes)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(RelayError::QueueFull(address)),
+            Err(TrySendError::Closed(_)) => Err(RelayError::NotConnected(address)),
+        }
+    }
+
+    // both ends see the link go away, the next frame either sends links them
+    // up again
+    fn disconnect(&self, address: PeerAddress) {
+        let name = match &address {
+            PeerAddress::Memory { name } => name,
+            _ => return,
+        };
+
+        let mut network = self.network.lock();
+        let linked = network
+            .links
+            .get_mut(&self.name)
+            .is_some_and(|links| links.remove(name));
+        if !linked {
+            return;
+        }
+        if let Some(links) = network.links.get_mut(name) {
+            links.remove(&self.name);
+        }
+        network.notify(&self.name, TransportEvent::Disconnected(address.clone()));
+        network.notify(name, TransportEvent::Disconnected(self.address()));
+    }
+
+    fn shutdown(&mut self) -> BoxFuture<'_, ()> {
+        let mut network = self.network.lock();
+        // dropping our sender ends our event stream
+        network.endpoints.remove(&self.name);
+        for peer in network.links.remove(&self.name).unwrap_or_default() {
+            if let Some(links) = network.links.get_mut(&peer) {
+                links.remove(&self.name);
+            }
+            network.notify(&peer, TransportEvent::Disconnected(self.address()));
+        }
+        future::ready(()).boxed()
+    }
+
+    fn set_queue_capacity(&mut self, capacity: usize) {
+        self.queue_capacity = capacity;
+    }
+}
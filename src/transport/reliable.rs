@@ -0,0 +1,510 @@
+use super::{Delivery, Overflow};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{
+    collections::{BTreeSet, HashMap},
+    hash::Hash,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/*
+
+    Every frame sent over udp is wrapped in an envelope, before it is split
+    into fragments
+
+        best effort    0u8, frame
+        reliable       1u8, session u32, sequence u32, frame
+        acknowledgment 2u8, session u32, sequence u32
+
+    A reliable frame is sent again, waiting twice as long each time (up to
+    `MAX_TIMEOUT`), until the peer acknowledges its sequence number or we
+    run out of attempts. A frame we give up on is dropped and logged, the
+    node that sent it isn't told. Like a connection's queue, only so many
+    frames to a peer can wait for acknowledgment, past that `Overflow`
+    decides between dropping the new frame and giving up on all of them.
+    The receiver acknowledges every copy it gets but only passes the first
+    one on. Sequence numbers start over whenever a node starts, so they're
+    scoped to a session picked at startup; that way a restarted peer isn't
+    mistaken for one replaying old frames.
+
+*/
+
+const BEST_EFFORT: u8 = 0;
+const RELIABLE: u8 = 1;
+const ACKNOWLEDGMENT: u8 = 2;
+
+// how far ahead of the oldest unseen sequence number we remember frames
+const WINDOW: u32 = 1024;
+// forget about senders we haven't heard from in this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+// the longest we wait for an acknowledgment before sending a frame again
+pub(crate) const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub(crate) type Session = u32;
+pub(crate) type Sequence = u32;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Config {
+    pub(crate) initial_timeout: Duration,
+    pub(crate) max_attempts: u32,
+}
+
+pub(crate) enum Envelope {
+    BestEffort(Bytes),
+    Reliable {
+        session: Session,
+        sequence: Sequence,
+        bytes: Bytes,
+    },
+    Acknowledgment {
+        session: Session,
+        sequence: Sequence,
+    },
+}
+impl Envelope {
+    pub(crate) fn encode(&self) -> Bytes {
+        match self {
+            Envelope::BestEffort(bytes) => {
+                let mut envelope = BytesMut::with_capacity(1 + bytes.len());
+                envelope.put_u8(BEST_EFFORT);
+                envelope.put_slice(bytes);
+                envelope.freeze()
+            }
+            Envelope::Reliable {
+                session,
+                sequence,
+                bytes,
+            } => {
+                let mut envelope = BytesMut::with_capacity(9 + bytes.len());
+                envelope.put_u8(RELIABLE);
+                envelope.put_u32(*session);
+                envelope.put_u32(*sequence);
+                envelope.put_slice(bytes);
+                envelope.freeze()
+            }
+            Envelope::Acknowledgment { session, sequence } => {
+                let mut envelope = BytesMut::with_capacity(9);
+                envelope.put_u8(ACKNOWLEDGMENT);
+                envelope.put_u32(*session);
+                envelope.put_u32(*sequence);
+                envelope.freeze()
+            }
+        }
+    }
+
+    pub(crate) fn decode(mut bytes: Bytes) -> Option<Envelope> {
+        if bytes.is_empty() {
+            return None;
+        }
+        match bytes.get_u8() {
+            BEST_EFFORT => Some(Envelope::BestEffort(bytes)),
+            RELIABLE if bytes.len() >= 8 => Some(Envelope::Reliable {
+                session: bytes.get_u32(),
+                sequence: bytes.get_u32(),
+                bytes,
+            }),
+            ACKNOWLEDGMENT if bytes.len() == 8 => Some(Envelope::Acknowledgment {
+                session: bytes.get_u32(),
+                sequence: bytes.get_u32(),
+            }),
+            _ => None, // unknown or truncated envelope
+        }
+    }
+}
+
+pub(crate) struct Due<A> {
+    pub(crate) resend: Vec<(A, Bytes)>,
+    // peers and sequence numbers of reliable frames we gave up on
+    pub(crate) abandoned: Vec<(A, Sequence)>,
+}
+
+struct Pending {
+    envelope: Bytes,
+    attempts: u32,
+    timeout: Duration,
+    deadline: Instant,
+}
+
+pub(crate) struct Sender<A> {
+    config: Config,
+    peer_capacity: usize,
+    overflow: Overflow,
+    session: Session,
+    next_sequence: HashMap<A, Sequence>,
+    pending: HashMap<(A, Sequence), Pending>,
+    // how many of `pending` are for every peer
+    unacknowledged: HashMap<A, usize>,
+}
+
+impl<A: Clone + Eq + Hash> Sender<A> {
+    pub(crate) fn new(config: Config, peer_capacity: usize, overflow: Overflow) -> Sender<A> {
+        // good enough to tell one run of a node from the next
+        let session = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as Session);
+        Sender {
+            config,
+            peer_capacity,
+            overflow,
+            session,
+            next_sequence: HashMap::new(),
+            pending: HashMap::new(),
+            unacknowledged: HashMap::new(),
+        }
+    }
+
+    // how often to check for frames due to be sent again
+    pub(crate) fn period(&self) -> Duration {
+        let initial_timeout = self.config.initial_timeout.min(MAX_TIMEOUT);
+        (initial_timeout / 4).max(Duration::from_millis(1))
+    }
+
+    // wraps a frame in its envelope, keeping a copy of reliable ones around
+    // until they are acknowledged; fails with what was done about it if too
+    // many frames to `address` are still unacknowledged to take another
+    pub(crate) fn wrap(
+        &mut self,
+        address: &A,
+        bytes: Bytes,
+        delivery: Delivery,
+    ) -> Result<Bytes, Overflow> {
+        if delivery == Delivery::BestEffort {
+            return Ok(Envelope::BestEffort(bytes).encode());
+        }
+        let unacknowledged = self.unacknowledged.get(address).copied().unwrap_or(0);
+        if unacknowledged >= self.peer_capacity {
+            if self.overflow == Overflow::Disconnect {
+                self.pending.retain(|(to, _), _| to != address);
+                self.unacknowledged.remove(address);
+            }
+            return Err(self.overflow);
+        }
+
+        let next_sequence = self.next_sequence.entry(address.clone()).or_default();
+        let sequence = *next_sequence;
+        *next_sequence = sequence.wrapping_add(1);
+
+        let envelope = Envelope::Reliable {
+            session: self.session,
+            sequence,
+            bytes,
+        }
+        .encode();
+        let timeout = self.config.initial_timeout.min(MAX_TIMEOUT);
+        let pending = Pending {
+            envelope: envelope.clone(),
+            attempts: 1,
+            timeout,
+            deadline: Instant::now() + timeout,
+        };
+        self.pending.insert((address.clone(), sequence), pending);
+        *self.unacknowledged.entry(address.clone()).or_default() += 1;
+        Ok(envelope)
+    }
+
+    pub(crate) fn acknowledged(&mut self, address: &A, session: Session, sequence: Sequence) {
+        // acknowledgments for an earlier run of this node mean nothing now
+        if session != self.session {
+            return;
+        }
+        if self.pending.remove(&(address.clone(), sequence)).is_some() {
+            self.forget(address);
+        }
+    }
+
+    fn forget(&mut self, address: &A) {
+        if let Some(unacknowledged) = self.unacknowledged.get_mut(address) {
+            *unacknowledged -= 1;
+            if *unacknowledged == 0 {
+                self.unacknowledged.remove(address);
+            }
+        }
+    }
+
+    // envelopes due to be sent again, and the frames we gave up on
+    pub(crate) fn due(&mut self) -> Due<A> {
+        let now = Instant::now();
+        let max_attempts = self.config.max_attempts;
+        let mut resend = Vec::new();
+        let mut abandoned = Vec::new();
+        self.pending.retain(|(address, sequence), pending| {
+            if pending.deadline > now {
+                return true;
+            }
+            if pending.attempts >= max_attempts {
+                abandoned.push((address.clone(), *sequence));
+                return false;
+            }
+            pending.attempts += 1;
+            pending.timeout = pending.timeout.saturating_mul(2).min(MAX_TIMEOUT);
+            pending.deadline = now + pending.timeout;
+            resend.push((address.clone(), pending.envelope.clone()));
+            true
+        });
+        for (address, _) in &abandoned {
+            self.forget(address);
+        }
+        Due { resend, abandoned }
+    }
+}
+
+struct Window {
+    session: Session,
+    // every sequence number before `base` has been seen or given up on
+    base: Sequence,
+    seen: BTreeSet<Sequence>,
+    last_heard: Instant,
+}
+impl Window {
+    fn new(session: Session) -> Window {
+        Window {
+            session,
+            base: 0,
+            seen: BTreeSet::new(),
+            last_heard: Instant::now(),
+        }
+    }
+
+    // returns false for frames we've already seen
+    fn insert(&mut self, sequence: Sequence) -> bool {
+        let offset = sequence.wrapping_sub(self.base);
+        if offset > Sequence::MAX / 2 {
+            return false;
+        }
+
+        // too far ahead, slide the window and stop waiting on older frames
+        if offset >= WINDOW {
+            self.base = sequence.wrapping_sub(WINDOW - 1);
+            let base = self.base;
+            self.seen
+                .retain(|seen| seen.wrapping_sub(base) <= Sequence::MAX / 2);
+        }
+
+        if !self.seen.insert(sequence) {
+            return false;
+        }
+        while self.seen.remove(&self.base) {
+            self.base = self.base.wrapping_add(1);
+        }
+        true
+    }
+}
+
+pub(crate) struct Receiver<A> {
+    windows: HashMap<A, Window>,
+}
+
+impl<A: Clone + Eq + Hash> Receiver<A> {
+    pub(crate) fn new() -> Receiver<A> {
+        Receiver {
+            windows: HashMap::new(),
+        }
+    }
+
+    // returns true the first time a reliable frame arrives
+    pub(crate) fn receive(&mut self, address: &A, session: Session, sequence: Sequence) -> bool {
+        let window = self
+            .windows
+            .entry(address.clone())
+            .or_insert_with(|| Window::new(session));
+        if window.session != session {
+            *window = Window::new(session);
+        }
+        window.last_heard = Instant::now();
+        window.insert(sequence)
+    }
+
+    pub(crate) fn expire(&mut self) {
+        self.windows
+            .retain(|_, window| window.last_heard.elapsed() < IDLE_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sender(initial_timeout: Duration, max_attempts: u32, overflow: Overflow) -> Sender<u8> {
+        let config = Config {
+            initial_timeout,
+            max_attempts,
+        };
+        Sender::new(config, 2, overflow)
+    }
+
+    fn sequence_of(envelope: Bytes) -> Sequence {
+        match Envelope::decode(envelope) {
+            Some(Envelope::Reliable { sequence, .. }) => sequence,
+            _ => panic!("not a reliable envelope"),
+        }
+    }
+
+    #[test]
+    fn envelopes_round_trip_and_truncated_ones_are_rejected() {
+        let bytes = Bytes::from_static(b"frame");
+        let reliable = Envelope::Reliable {
+            session: 3,
+            sequence: 4,
+            bytes: bytes.clone(),
+        }
+        .encode();
+        match Envelope::decode(reliable.clone()) {
+            Some(Envelope::Reliable {
+                session: 3,
+                sequence: 4,
+                bytes: decoded,
+            }) => assert_eq!(decoded, bytes),
+            _ => panic!("reliable envelope didn't round trip"),
+        }
+        let acknowledgment = Envelope::Acknowledgment {
+            session: 3,
+            sequence: 4,
+        }
+        .encode();
+        assert!(matches!(
+            Envelope::decode(acknowledgment.clone()),
+            Some(Envelope::Acknowledgment {
+                session: 3,
+                sequence: 4
+            })
+        ));
+
+        assert!(Envelope::decode(Bytes::new()).is_none());
+        assert!(Envelope::decode(reliable.slice(..8)).is_none());
+        assert!(Envelope::decode(acknowledgment.slice(..8)).is_none());
+        assert!(Envelope::decode(Bytes::from_static(&[9])).is_none());
+    }
+
+    #[test]
+    fn best_effort_frames_are_not_kept() {
+        let mut sender = sender(Duration::ZERO, 3, Overflow::Drop);
+        let envelope = sender.wrap(&1, Bytes::from_static(b"x"), Delivery::BestEffort);
+        assert!(matches!(
+            Envelope::decode(envelope.ok().unwrap()),
+            Some(Envelope::BestEffort(_))
+        ));
+        assert!(sender.pending.is_empty());
+        assert!(sender.due().resend.is_empty());
+    }
+
+    #[test]
+    fn reliable_frames_are_resent_until_acknowledged() {
+        let mut sender = sender(Duration::ZERO, 5, Overflow::Drop);
+        let envelope = sender.wrap(&1, Bytes::from_static(b"x"), Delivery::Reliable);
+        let sequence = sequence_of(envelope.ok().unwrap());
+
+        let due = sender.due();
+        assert_eq!(due.resend.len(), 1);
+        assert!(due.abandoned.is_empty());
+
+        // an acknowledgment from another run of the peer's node is ignored
+        sender.acknowledged(&1, sender.session.wrapping_add(1), sequence);
+        assert_eq!(sender.due().resend.len(), 1);
+
+        sender.acknowledged(&1, sender.session, sequence);
+        assert!(sender.due().resend.is_empty());
+        assert!(sender.unacknowledged.is_empty());
+    }
+
+    #[test]
+    fn frames_are_abandoned_after_the_last_attempt() {
+        let mut sender = sender(Duration::ZERO, 3, Overflow::Drop);
+        let envelope = sender.wrap(&1, Bytes::from_static(b"x"), Delivery::Reliable);
+        let sequence = sequence_of(envelope.ok().unwrap());
+
+        assert_eq!(sender.due().resend.len(), 1);
+        assert_eq!(sender.due().resend.len(), 1);
+        let due = sender.due();
+        assert!(due.resend.is_empty());
+        assert_eq!(due.abandoned, vec![(1, sequence)]);
+        assert!(sender.pending.is_empty());
+        assert!(sender.unacknowledged.is_empty());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut sender = sender(Duration::from_secs(40), 5, Overflow::Drop);
+        let _ = sender.wrap(&1, Bytes::from_static(b"x"), Delivery::Reliable);
+        let pending = sender.pending.values_mut().next().unwrap();
+        assert_eq!(pending.timeout, Duration::from_secs(40));
+        pending.deadline = Instant::now();
+        assert_eq!(sender.due().resend.len(), 1);
+        let pending = sender.pending.values().next().unwrap();
+        assert_eq!(pending.timeout, MAX_TIMEOUT);
+
+        // an initial timeout past the cap is capped too, and can't overflow
+        let mut capped = self::sender(Duration::MAX, 5, Overflow::Drop);
+        let _ = capped.wrap(&1, Bytes::from_static(b"x"), Delivery::Reliable);
+        let pending = capped.pending.values().next().unwrap();
+        assert_eq!(pending.timeout, MAX_TIMEOUT);
+    }
+
+    #[test]
+    fn a_full_peer_drops_or_gives_up_on_frames() {
+        let mut dropping = sender(Duration::ZERO, 5, Overflow::Drop);
+        for _ in 0..2 {
+            assert!(dropping.wrap(&1, Bytes::new(), Delivery::Reliable).is_ok());
+        }
+        let overflow = dropping.wrap(&1, Bytes::new(), Delivery::Reliable);
+        assert_eq!(overflow.err(), Some(Overflow::Drop));
+        assert_eq!(dropping.pending.len(), 2);
+        // other peers have their own room
+        assert!(dropping.wrap(&2, Bytes::new(), Delivery::Reliable).is_ok());
+
+        let mut disconnecting = sender(Duration::ZERO, 5, Overflow::Disconnect);
+        for _ in 0..2 {
+            assert!(disconnecting
+                .wrap(&1, Bytes::new(), Delivery::Reliable)
+                .is_ok());
+        }
+        let overflow = disconnecting.wrap(&1, Bytes::new(), Delivery::Reliable);
+        assert_eq!(overflow.err(), Some(Overflow::Disconnect));
+        assert!(disconnecting.pending.is_empty());
+        assert!(disconnecting.unacknowledged.is_empty());
+    }
+
+    #[test]
+    fn windows_pass_each_sequence_on_once() {
+        let mut window = Window::new(1);
+        assert!(window.insert(1));
+        assert!(window.insert(0));
+        assert_eq!(window.base, 2);
+        assert!(!window.insert(0));
+        assert!(!window.insert(1));
+        assert!(window.insert(3));
+        assert!(!window.insert(3));
+        assert!(window.insert(2));
+        assert_eq!(window.base, 4);
+        assert!(window.seen.is_empty());
+    }
+
+    #[test]
+    fn windows_slide_past_frames_too_far_behind() {
+        let mut window = Window::new(1);
+        assert!(window.insert(5));
+        assert!(window.insert(WINDOW + 10));
+        assert_eq!(window.base, 11);
+        // never seen, but we stopped waiting for it
+        assert!(!window.insert(3));
+        assert!(window.insert(11));
+    }
+
+    #[test]
+    fn windows_wrap_around() {
+        let mut window = Window::new(1);
+        window.base = Sequence::MAX;
+        assert!(window.insert(Sequence::MAX));
+        assert_eq!(window.base, 0);
+        assert!(window.insert(0));
+        assert!(!window.insert(Sequence::MAX));
+    }
+
+    #[test]
+    fn a_new_session_starts_a_new_window() {
+        let mut receiver = Receiver::new();
+        assert!(receiver.receive(&1, 1, 0));
+        assert!(!receiver.receive(&1, 1, 0));
+        assert!(receiver.receive(&1, 2, 0));
+        // windows are kept per sender
+        assert!(receiver.receive(&2, 2, 0));
+    }
+}
@@ -1,111 +1,155 @@
 use super::{
-    tcp, udp, FrameRx, FrameTx, Message, PeerAddress, TransportFrame, TransportProtocol,
-    TransportRx, TransportTx,
+    tcp::TcpTransport,
+    udp::UdpTransport,
+    unix::{UnixDatagramTransport, UnixStreamTransport},
+    Config, Delivery, Message, PeerAddress, Transport, TransportRx,
+};
+use crate::RelayError;
+use futures::{
+    future::{self, BoxFuture, FutureExt},
+    stream::SelectAll,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
 };
-use tokio::sync::mpsc::unbounded_channel;
 
 pub(crate) struct Router {
-    tcp_out_frame_tx: FrameTx,
-    udp_out_frame_tx: FrameTx,
+    tcp_address: Option<SocketAddr>,
+    udp_address: Option<SocketAddr>,
+    unix_stream_path: Option<PathBuf>,
+    unix_datagram_path: Option<PathBuf>,
+    transports: HashMap<String, Box<dyn Transport>>,
 }
 impl Router {
-    pub fn new(port: u16, buffer_size: usize) -> (Router, TransportRx) {
-        let (tcp_transport_tx, transport_rx) = unbounded_channel();
-        let udp_transport_tx = tcp_transport_tx.clone();
+    // binds the built in transports from `config`, then any others we're
+    // given, so failures are reported to the caller
+    pub async fn new(
+        config: Config,
+        custom: Vec<Box<dyn Transport>>,
+    ) -> Result<(Router, TransportRx), RelayError> {
+        let mut events = SelectAll::new();
+        let mut router = Router {
+            tcp_address: None,
+            udp_address: None,
+            unix_stream_path: config.unix_stream_path.clone(),
+            unix_datagram_path: config.unix_datagram_path.clone(),
+            transports: HashMap::new(),
+        };
 
-        let (tcp_in_frame_tx, tcp_in_frame_rx) = unbounded_channel();
-        let (tcp_out_frame_tx, tcp_out_frame_rx) = unbounded_channel();
-        tokio::spawn(tcp::listen(port, tcp_in_frame_tx, tcp_out_frame_rx));
-        tokio::spawn(Router::handle_tcp_incoming(
-            tcp_in_frame_rx,
-            tcp_transport_tx,
-        ));
+        if config.tcp {
+            let mut tcp = TcpTransport::new(config.tcp_address, config.queues.clone());
+            events.push(tcp.bind().await?);
+            router.tcp_address = Some(tcp.local_address());
+            router
+                .transports
+                .insert(tcp.scheme().to_string(), Box::new(tcp));
+        }
+        if config.udp {
+            let mut udp = UdpTransport::new(
+                config.udp_address,
+                config.udp_buffer_size,
+                config.udp_mtu,
+                config.udp_reassembly,
+                config.udp_reliability,
+                config.queues.clone(),
+            );
+            match udp.bind().await {
+                Ok(udp_events) => events.push(udp_events),
+                Err(err) => {
+                    router.shutdown().await;
+                    return Err(err);
+                }
+            }
+            router.udp_address = Some(udp.local_address());
+            router
+                .transports
+                .insert(udp.scheme().to_string(), Box::new(udp));
+        }
 
-        let (udp_in_frame_tx, udp_in_frame_rx) = unbounded_channel();
-        let (udp_out_frame_tx, udp_out_frame_rx) = unbounded_channel();
-        tokio::spawn(udp::listen(
-            port,
-            buffer_size,
-            udp_in_frame_tx,
-            udp_out_frame_rx,
-        ));
-        tokio::spawn(Router::handle_udp_incoming(
-            udp_in_frame_rx,
-            udp_transport_tx,
-        ));
+        let mut transports: Vec<Box<dyn Transport>> = Vec::new();
+        if let Some(path) = config.unix_stream_path {
+            let queues = config.queues.clone();
+            transports.push(Box::new(UnixStreamTransport::new(path, queues)));
+        }
+        if let Some(path) = config.unix_datagram_path {
+            let capacity = config.queues.capacity;
+            transports.push(Box::new(UnixDatagramTransport::new(path, capacity)));
+        }
+        transports.extend(custom);
 
-        let router = Router {
-            tcp_out_frame_tx,
-            udp_out_frame_tx,
-        };
-        let transport_msg_stream = transport_rx.into();
+        for mut transport in transports {
+            let bound = if router.transports.contains_key(transport.scheme()) {
+                Err(RelayError::DuplicateScheme(transport.scheme().to_string()))
+            } else {
+                transport.set_queue_capacity(config.queues.capacity);
+                transport.bind().await
+            };
+            match bound {
+                Ok(transport_events) => events.push(transport_events),
+                Err(err) => {
+                    // don't leave the transports we already bound running
+                    router.shutdown().await;
+                    return Err(err);
+                }
+            }
+            router
+                .transports
+                .insert(transport.scheme().to_string(), transport);
+        }
 
-        (router, transport_msg_stream)
+        Ok((router, events))
     }
 
-    pub fn send(&self, message: Message) {
-        let Message {
-            address,
-            payload: bytes,
-        } = message;
-        match address {
-            PeerAddress::Unix {
-                address: _,
-                protocol,
-            } => match protocol {
-                TransportProtocol::Datagram => panic!("unix sockets not supported yet"),
-                TransportProtocol::Stream => panic!("unix sockets not supported yet"),
-            },
-            PeerAddress::Internet { address, protocol } => match protocol {
-                TransportProtocol::Datagram => {
-                    if let Err(_) = self
-                        .udp_out_frame_tx
-                        .send(TransportFrame { address, bytes })
-                    {
-                        // can't send
-                    }
-                }
-                TransportProtocol::Stream => {
-                    if let Err(_) = self
-                        .tcp_out_frame_tx
-                        .send(TransportFrame { address, bytes })
-                    {
-                        // can't send
-                    }
-                }
-            },
+    pub fn tcp_address(&self) -> Option<SocketAddr> {
+        self.tcp_address
+    }
+
+    pub fn udp_address(&self) -> Option<SocketAddr> {
+        self.udp_address
+    }
+
+    pub fn unix_stream_path(&self) -> Option<&Path> {
+        self.unix_stream_path.as_deref()
+    }
+
+    pub fn unix_datagram_path(&self) -> Option<&Path> {
+        self.unix_datagram_path.as_deref()
+    }
+
+    // stop accepting new work, let every frame already queued go out, and
+    // wait for all of the transports to stop
+    pub async fn shutdown(&mut self) {
+        for transport in self.transports.values_mut() {
+            transport.shutdown().await;
         }
     }
 
-    async fn handle_tcp_incoming(mut tcp_recv_rx: FrameRx, transport_tx: TransportTx) {
-        while let Some(tcp_message) = tcp_recv_rx.recv().await {
-            let TransportFrame {
-                address,
-                bytes: payload,
-            } = tcp_message;
-            let address = PeerAddress::Internet {
-                address,
-                protocol: TransportProtocol::Stream,
-            };
-            if let Err(_) = transport_tx.send(Message { address, payload }) {
-                // can't send any more transport messages
-            }
+    // resolves once the transport has room for the message
+    pub fn send(
+        &self,
+        message: Message,
+        delivery: Delivery,
+    ) -> BoxFuture<'static, Result<(), RelayError>> {
+        let Message { address, payload } = message;
+        match self.transports.get(address.scheme()) {
+            Some(transport) => transport.send(address, payload, delivery),
+            None => future::ready(Err(RelayError::UnsupportedAddress(address))).boxed(),
         }
     }
 
-    async fn handle_udp_incoming(mut udp_recv_rx: FrameRx, transport_tx: TransportTx) {
-        while let Some(udp_message) = udp_recv_rx.recv().await {
-            let TransportFrame {
-                address,
-                bytes: payload,
-            } = udp_message;
-            let address = PeerAddress::Internet {
-                address,
-                protocol: TransportProtocol::Datagram,
-            };
-            if let Err(_) = transport_tx.send(Message { address, payload }) {
-                // can't send any more transport messages
-            }
+    pub fn try_send(&self, message: Message, delivery: Delivery) -> Result<(), RelayError> {
+        let Message { address, payload } = message;
+        match self.transports.get(address.scheme()) {
+            Some(transport) => transport.try_send(address, payload, delivery),
+            None => Err(RelayError::UnsupportedAddress(address)),
+        }
+    }
+
+    pub fn disconnect(&self, address: &PeerAddress) {
+        if let Some(transport) = self.transports.get(address.scheme()) {
+            transport.disconnect(address.clone());
         }
     }
 }
@@ -1,131 +1,163 @@
-use super::{FrameRx, FrameTx, TransportFrame};
-use bytes::Bytes;
-use futures::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt,
+use super::connections::{self, framed, Acceptor};
+use super::{
+    events, internet_address, queue, try_queue, FrameRx, FrameTx, InboundTx, Queues, TransportFrame,
+};
+use crate::transport::{
+    Delivery, PeerAddress, Transport, TransportEvents, TransportProtocol, TCP_SCHEME,
 };
-use std::{collections::HashMap, net::SocketAddr};
+use crate::RelayError;
+use bytes::Bytes;
+use futures::future::{self, BoxFuture, FutureExt, TryFutureExt};
+use std::net::SocketAddr;
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
 };
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug_span, warn, Instrument};
 
-enum ConnectionMessage {
-    New {
-        address: SocketAddr,
-        bytes_tx: BytesTx,
-    },
-    Send {
-        address: SocketAddr,
-        bytes: Bytes,
-    },
+pub(crate) struct TcpTransport {
+    address: SocketAddr,
+    queues: Queues,
+    out_frame_tx: Option<FrameTx>,
+    disconnect_tx: Option<UnboundedSender<SocketAddr>>,
+    shutdown: CancellationToken,
+    task: Option<JoinHandle<()>>,
 }
+impl TcpTransport {
+    pub(crate) fn new(address: SocketAddr, queues: Queues) -> TcpTransport {
+        TcpTransport {
+            address,
+            queues,
+            out_frame_tx: None,
+            disconnect_tx: None,
+            shutdown: CancellationToken::new(),
+            task: None,
+        }
+    }
 
-type ConnMsgTx = UnboundedSender<ConnectionMessage>;
-type ConnMessageRx = UnboundedReceiver<ConnectionMessage>;
-
-type BytesTx = UnboundedSender<Bytes>;
-type BytesRx = UnboundedReceiver<Bytes>;
-
-type SplitTcpStream = SplitStream<Framed<TcpStream, LengthDelimitedCodec>>;
-type SplitTcpSink = SplitSink<Framed<TcpStream, LengthDelimitedCodec>, Bytes>;
-
-pub(super) async fn listen(port: u16, in_frame_tx: FrameTx, out_frame_rx: FrameRx) {
-    let address = SocketAddr::from(([127, 0, 0, 1], port));
-    let listener = TcpListener::bind(address).await.expect("couldn't bind tcp");
-    println!("{} Listening", addr_str(address));
-
-    let (conn_msg_tx, conn_msg_rx) = mpsc::unbounded_channel();
-    tokio::select! {
-        () = accept_connections(listener, in_frame_tx, conn_msg_tx.clone()) => {}
-        () = process_connection_messages(conn_msg_rx) => {},
-        () = relay_outgoing_bytes(out_frame_rx, conn_msg_tx) => {},
-    };
+    // the address we're actually listening on, once bound
+    pub(crate) fn local_address(&self) -> SocketAddr {
+        self.address
+    }
 }
+impl Transport for TcpTransport {
+    fn scheme(&self) -> &str {
+        TCP_SCHEME
+    }
 
-async fn accept_connections(listener: TcpListener, in_frame_tx: FrameTx, conn_msg_tx: ConnMsgTx) {
-    loop {
-        match listener.accept().await {
-            Ok((tcp, address)) => {
-                let in_frame_tx = in_frame_tx.clone();
+    fn bind(&mut self) -> BoxFuture<'_, Result<TransportEvents, RelayError>> {
+        async move {
+            let listener = TcpListener::bind(self.address).await?;
+            self.address = listener.local_addr()?;
 
-                let (sink, stream) = Framed::new(tcp, LengthDelimitedCodec::new()).split();
-                let (bytes_tx, bytes_rx) = mpsc::unbounded_channel::<Bytes>();
-                tokio::spawn(handle_tcp_stream(stream, address, in_frame_tx));
-                tokio::spawn(handle_tcp_sink(sink, address, bytes_rx));
+            let (inbound_tx, inbound_rx) = mpsc::channel(self.queues.capacity);
+            let (out_frame_tx, out_frame_rx) = mpsc::channel(self.queues.capacity);
+            let (disconnect_tx, disconnect_rx) = mpsc::unbounded_channel();
+            let shutdown = self.shutdown.clone();
+            self.task = Some(tokio::spawn(listen(
+                listener,
+                self.queues.clone(),
+                inbound_tx,
+                out_frame_rx,
+                disconnect_rx,
+                shutdown,
+            )));
+            self.out_frame_tx = Some(out_frame_tx);
+            self.disconnect_tx = Some(disconnect_tx);
+            Ok(events(
+                inbound_rx,
+                internet_address(TransportProtocol::Stream),
+            ))
+        }
+        .boxed()
+    }
 
-                if let Err(_) = conn_msg_tx.send(ConnectionMessage::New { address, bytes_tx }) {
-                    println!("Error: Couldn't register new tcp connection");
-                    println!("       Connection message receiver is closed");
-                }
+    fn send(
+        &self,
+        address: PeerAddress,
+        bytes: Bytes,
+        _delivery: Delivery,
+    ) -> BoxFuture<'static, Result<(), RelayError>> {
+        match address {
+            PeerAddress::Internet { address, .. } => {
+                queue(&self.out_frame_tx, TransportFrame { address, bytes })
             }
-            Err(err) => println!("transport::tcp couldn't accept client: {:?}", err),
+            _ => future::ready(Err(RelayError::UnsupportedAddress(address))).boxed(),
         }
     }
-}
 
-async fn handle_tcp_stream(mut stream: SplitTcpStream, address: SocketAddr, in_frame_tx: FrameTx) {
-    while let Some(frame) = stream.next().await {
-        match frame {
-            Ok(bytes) => {
-                let len = bytes.len();
-                let bytes = bytes.freeze();
-                let msg = TransportFrame { address, bytes };
-                match in_frame_tx.send(msg) {
-                    Ok(()) => println!("{} Received {} bytes", addr_str(address), len),
-                    Err(_) => {
-                        println!("{} Error: Frame receiver is closed", addr_str(address));
-                        return;
-                    }
-                }
+    fn try_send(
+        &self,
+        address: PeerAddress,
+        bytes: Bytes,
+        _delivery: Delivery,
+    ) -> Result<(), RelayError> {
+        match &address {
+            PeerAddress::Internet { address: to, .. } => {
+                let frame = TransportFrame {
+                    address: *to,
+                    bytes,
+                };
+                try_queue(&self.out_frame_tx, address, frame)
             }
-            Err(err) => println!("{} Receive Error: {}", addr_str(address), err),
+            _ => Err(RelayError::UnsupportedAddress(address)),
         }
     }
-}
 
-async fn handle_tcp_sink(mut sink: SplitTcpSink, address: SocketAddr, mut bytes_rx: BytesRx) {
-    while let Some(bytes) = bytes_rx.recv().await {
-        let count = bytes.len();
-        match sink.send(bytes).await {
-            Ok(()) => println!("{} Sent {} bytes", addr_str(address), count),
-            Err(err) => println!("{} Send Error: {} ", addr_str(address), err),
+    fn disconnect(&self, address: PeerAddress) {
+        if let (PeerAddress::Internet { address, .. }, Some(disconnect_tx)) =
+            (address, &self.disconnect_tx)
+        {
+            let _ = disconnect_tx.send(address);
         }
     }
-}
 
-async fn process_connection_messages(mut conn_msg_rx: ConnMessageRx) {
-    let mut map = HashMap::new();
-    while let Some(message) = conn_msg_rx.recv().await {
-        match message {
-            ConnectionMessage::New { address, bytes_tx } => {
-                map.insert(address, bytes_tx);
+    fn shutdown(&mut self) -> BoxFuture<'_, ()> {
+        async move {
+            self.out_frame_tx = None;
+            self.disconnect_tx = None;
+            self.shutdown.cancel();
+            if let Some(task) = self.task.take() {
+                let _ = task.await;
             }
-            ConnectionMessage::Send { address, bytes } => match map.get(&address) {
-                Some(bytes_tx) => match bytes_tx.send(bytes) {
-                    Ok(()) => {}
-                    Err(_) => {
-                        println!("{} Error: Byte receiver is closed", addr_str(address));
-                        map.remove(&address);
-                    }
-                },
-                None => println!("{} Error: No registered byte receiver", addr_str(address)),
-            },
         }
+        .boxed()
     }
 }
 
-async fn relay_outgoing_bytes(mut out_frame_tx: FrameRx, conn_msg_tx: ConnMsgTx) {
-    while let Some(message) = out_frame_tx.recv().await {
-        let TransportFrame { address, bytes } = message;
-        if let Err(_) = conn_msg_tx.send(ConnectionMessage::Send { address, bytes }) {
-            println!("Error: Connection message receiver is closed");
-            return;
-        };
-    }
+// see `connections` for how connections are handled once accepted or dialed
+async fn listen(
+    listener: TcpListener,
+    queues: Queues,
+    inbound_tx: InboundTx,
+    out_frame_rx: FrameRx,
+    disconnect_rx: UnboundedReceiver<SocketAddr>,
+    shutdown: CancellationToken,
+) {
+    let span = match listener.local_addr() {
+        Ok(local) => debug_span!("tcp", %local),
+        Err(_) => debug_span!("tcp"),
+    };
+    connections::run(
+        |acceptor| accept_connections(listener, acceptor),
+        |address| TcpStream::connect(*address).map_ok(framed).boxed(),
+        queues,
+        inbound_tx,
+        out_frame_rx,
+        disconnect_rx,
+        shutdown,
+    )
+    .instrument(span)
+    .await
 }
 
-fn addr_str(address: SocketAddr) -> String {
-    format!("ip{{{}}}->tcp{{{}}}", address.ip(), address.port(),)
+async fn accept_connections(listener: TcpListener, acceptor: Acceptor<SocketAddr>) {
+    loop {
+        match listener.accept().await {
+            Ok((tcp, address)) => acceptor.accept(address, framed(tcp)).await,
+            Err(err) => warn!(error = %err, "couldn't accept client"),
+        }
+    }
 }
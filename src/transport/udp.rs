@@ -1,54 +1,366 @@
-use super::{FrameRx, FrameTx, TransportFrame};
-use std::{net::SocketAddr, sync::Arc};
-use tokio::net::UdpSocket;
+use super::fragment::{self, FragmentId, Limits, Reassembler};
+use super::reliable::{self, Envelope, Receiver, Sender, Sequence, Session};
+use super::{
+    events, internet_address, queue, receive_space, try_queue, Datagram, DatagramRx, DatagramTx,
+    Inbound, InboundTx, TransportFrame,
+};
+use crate::transport::{
+    Delivery, Overflow, PeerAddress, Queues, Transport, TransportEvents, TransportProtocol,
+    MAX_UDP_DATAGRAM, UDP_SCHEME,
+};
+use crate::RelayError;
+use bytes::{Bytes, BytesMut};
+use futures::future::{self, BoxFuture, FutureExt};
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+    time,
+};
+use tracing::{debug, trace, warn, Instrument};
 
-pub(super) async fn listen(
-    port: u16,
+// what the receiving side needs the sending side to do
+enum Control {
+    Acknowledge {
+        address: SocketAddr,
+        session: Session,
+        sequence: Sequence,
+    },
+    Acknowledged {
+        address: SocketAddr,
+        session: Session,
+        sequence: Sequence,
+    },
+}
+
+type ControlTx = UnboundedSender<Control>;
+type ControlRx = UnboundedReceiver<Control>;
+
+pub(crate) struct UdpTransport {
+    address: SocketAddr,
     buffer_size: usize,
-    in_frame_tx: FrameTx,
-    out_frame_rx: FrameRx,
+    mtu: usize,
+    limits: Limits,
+    reliability: reliable::Config,
+    queues: Queues,
+    out_frame_tx: Option<DatagramTx>,
+    task: Option<JoinHandle<()>>,
+}
+impl UdpTransport {
+    pub(crate) fn new(
+        address: SocketAddr,
+        buffer_size: usize,
+        mtu: usize,
+        limits: Limits,
+        reliability: reliable::Config,
+        queues: Queues,
+    ) -> UdpTransport {
+        UdpTransport {
+            address,
+            buffer_size,
+            mtu,
+            limits,
+            reliability,
+            queues,
+            out_frame_tx: None,
+            task: None,
+        }
+    }
+
+    // the address we're actually listening on, once bound
+    pub(crate) fn local_address(&self) -> SocketAddr {
+        self.address
+    }
+}
+impl Transport for UdpTransport {
+    fn scheme(&self) -> &str {
+        UDP_SCHEME
+    }
+
+    fn bind(&mut self) -> BoxFuture<'_, Result<TransportEvents, RelayError>> {
+        async move {
+            let socket = Arc::new(UdpSocket::bind(self.address).await?);
+            self.address = socket.local_addr()?;
+
+            let (inbound_tx, inbound_rx) = mpsc::channel(self.queues.capacity);
+            let (out_frame_tx, out_frame_rx) = mpsc::channel(self.queues.capacity);
+            let reliable = Sender::new(
+                self.reliability.clone(),
+                self.queues.peer_capacity,
+                self.queues.overflow,
+            );
+            self.task = Some(tokio::spawn(listen(
+                socket,
+                self.buffer_size,
+                self.mtu,
+                self.limits.clone(),
+                reliable,
+                inbound_tx,
+                out_frame_rx,
+            )));
+            self.out_frame_tx = Some(out_frame_tx);
+            Ok(events(
+                inbound_rx,
+                internet_address(TransportProtocol::Datagram),
+            ))
+        }
+        .boxed()
+    }
+
+    fn send(
+        &self,
+        address: PeerAddress,
+        bytes: Bytes,
+        delivery: Delivery,
+    ) -> BoxFuture<'static, Result<(), RelayError>> {
+        match address {
+            PeerAddress::Internet { address, .. } => {
+                let frame = TransportFrame { address, bytes };
+                queue(&self.out_frame_tx, Datagram { frame, delivery })
+            }
+            _ => future::ready(Err(RelayError::UnsupportedAddress(address))).boxed(),
+        }
+    }
+
+    fn try_send(
+        &self,
+        address: PeerAddress,
+        bytes: Bytes,
+        delivery: Delivery,
+    ) -> Result<(), RelayError> {
+        match &address {
+            PeerAddress::Internet { address: to, .. } => {
+                let frame = TransportFrame {
+                    address: *to,
+                    bytes,
+                };
+                try_queue(&self.out_frame_tx, address, Datagram { frame, delivery })
+            }
+            _ => Err(RelayError::UnsupportedAddress(address)),
+        }
+    }
+
+    fn disconnect(&self, _address: PeerAddress) {} // no connections to drop
+
+    fn shutdown(&mut self) -> BoxFuture<'_, ()> {
+        async move {
+            // the task stops once everything queued up has been sent
+            self.out_frame_tx = None;
+            if let Some(task) = self.task.take() {
+                let _ = task.await;
+            }
+        }
+        .boxed()
+    }
+}
+
+/*
+
+    Frames are wrapped in an envelope (see `reliable`), and envelopes larger
+    than the mtu are split over several datagrams (see `fragment`) and put
+    back together on the receiving end. Peers may be configured with a
+    bigger mtu than ours, so we always receive room for the largest
+    datagram udp can carry and nothing gets truncated.
+
+*/
+async fn listen(
+    socket: Arc<UdpSocket>,
+    buffer_size: usize,
+    mtu: usize,
+    limits: Limits,
+    reliable: Sender<SocketAddr>,
+    inbound_tx: InboundTx,
+    out_frame_rx: DatagramRx,
 ) {
-    let address = SocketAddr::from(([127, 0, 0, 1], port));
-    let socket = UdpSocket::bind(address).await.expect("couldn't bind udp");
-    let sender = Arc::new(socket);
+    let sender = socket;
     let listener = sender.clone();
-    println!("{} Listening", addr_str(address));
-
-    tokio::select! {
-        () = self::handle_incoming_data(buffer_size, listener, in_frame_tx) => {},
-        () = self::handle_outgoing_data(out_frame_rx, sender) => {},
+    let span = match listener.local_addr() {
+        Ok(local) => tracing::debug_span!("udp", %local),
+        Err(_) => tracing::debug_span!("udp"),
     };
+    let (control_tx, control_rx) = mpsc::unbounded_channel();
+
+    async move {
+        debug!("listening");
+        tokio::select! {
+            () = self::handle_incoming_data(buffer_size, mtu, limits, listener, inbound_tx, control_tx) => {},
+            () = self::handle_outgoing_data(out_frame_rx, control_rx, mtu, reliable, sender) => {},
+        };
+        debug!("stopped");
+    }
+    .instrument(span)
+    .await
 }
 
-async fn handle_incoming_data(buffer_size: usize, listener: Arc<UdpSocket>, in_frame_tx: FrameTx) {
-    let mut buf = vec![0u8; buffer_size];
+async fn handle_incoming_data(
+    buffer_size: usize,
+    mtu: usize,
+    limits: Limits,
+    listener: Arc<UdpSocket>,
+    inbound_tx: InboundTx,
+    control_tx: ControlTx,
+) {
+    let mut buf = BytesMut::new();
+    // check often enough that nothing outlives the timeout by much
+    let period = (limits.timeout / 2).max(Duration::from_millis(1));
+    let mut expiry = time::interval(period);
+    let mut reassembler = Reassembler::<SocketAddr>::new(limits, mtu);
+    let mut receiver = Receiver::<SocketAddr>::new();
     loop {
-        match listener.recv_from(&mut buf).await {
-            Ok((len, address)) => {
-                let buf = Vec::from(&buf[0..len]);
-                let bytes = buf.into();
-                match in_frame_tx.send(TransportFrame { address, bytes }) {
-                    Ok(()) => println!("{} Received {} bytes", addr_str(address), len),
-                    Err(err) => {
-                        println!("{} Received {} bytes ERR: {}", addr_str(address), len, err)
-                    }
+        // each datagram is split off the buffer and handed on as is
+        let received = tokio::select! {
+            received = listener.recv_from(receive_space(&mut buf, MAX_UDP_DATAGRAM, buffer_size)) => received,
+            _ = expiry.tick() => {
+                reassembler.expire();
+                receiver.expire();
+                continue;
+            }
+        };
+        let (len, address) = match received {
+            Ok(received) => received,
+            Err(err) => {
+                warn!(error = %err, "receive failed");
+                continue;
+            }
+        };
+
+        let datagram = buf.split_to(len).freeze();
+        let envelope = match reassembler.receive(&address, datagram) {
+            Some(envelope) => envelope,
+            None => {
+                trace!(peer = %address, bytes = len, "received fragment");
+                continue;
+            }
+        };
+        let bytes = match Envelope::decode(envelope) {
+            Some(Envelope::BestEffort(bytes)) => bytes,
+            Some(Envelope::Reliable {
+                session,
+                sequence,
+                bytes,
+            }) => {
+                let _ = control_tx.send(Control::Acknowledge {
+                    address,
+                    session,
+                    sequence,
+                });
+                if !receiver.receive(&address, session, sequence) {
+                    trace!(peer = %address, sequence, "dropping duplicate");
+                    continue;
                 }
+                bytes
+            }
+            Some(Envelope::Acknowledgment { session, sequence }) => {
+                let _ = control_tx.send(Control::Acknowledged {
+                    address,
+                    session,
+                    sequence,
+                });
+                continue;
             }
-            Err(_) => println!("UDP Receive failure"),
+            None => {
+                warn!(peer = %address, bytes = len, "dropping malformed envelope");
+                continue;
+            }
+        };
+
+        // waiting here when the node falls behind leaves datagrams piling up
+        // in the socket's buffer, and past that the kernel drops them
+        let len = bytes.len();
+        match inbound_tx
+            .send(Inbound::Frame(TransportFrame { address, bytes }))
+            .await
+        {
+            Ok(()) => trace!(peer = %address, bytes = len, "received"),
+            Err(_) => warn!(peer = %address, bytes = len, "frame receiver is closed"),
         }
     }
 }
 
-async fn handle_outgoing_data(mut out_frame_rx: FrameRx, sender: Arc<UdpSocket>) {
-    while let Some(message) = out_frame_rx.recv().await {
-        let TransportFrame { address, bytes } = message;
-        match sender.send_to(bytes.as_ref(), address).await {
-            Ok(len) => println!("{} Send {} bytes", addr_str(address), len),
-            Err(_) => println!("{} Send failure", addr_str(address)),
+async fn handle_outgoing_data(
+    mut out_frame_rx: DatagramRx,
+    mut control_rx: ControlRx,
+    mtu: usize,
+    mut sender: Sender<SocketAddr>,
+    socket: Arc<UdpSocket>,
+) {
+    let mut retransmit = time::interval(sender.period());
+    let mut next_id: FragmentId = 0;
+    loop {
+        let (address, envelope) = tokio::select! {
+            datagram = out_frame_rx.recv() => match datagram {
+                Some(Datagram { frame, delivery }) => {
+                    let TransportFrame { address, bytes } = frame;
+                    match sender.wrap(&address, bytes, delivery) {
+                        Ok(envelope) => (address, envelope),
+                        Err(Overflow::Drop) => {
+                            warn!(peer = %address, "too many unacknowledged frames, dropping frame");
+                            continue;
+                        }
+                        Err(Overflow::Disconnect) => {
+                            warn!(peer = %address, "too many unacknowledged frames, giving up on them");
+                            continue;
+                        }
+                    }
+                }
+                None => return,
+            },
+            Some(control) = control_rx.recv() => match control {
+                Control::Acknowledge { address, session, sequence } => {
+                    (address, Envelope::Acknowledgment { session, sequence }.encode())
+                }
+                Control::Acknowledged { address, session, sequence } => {
+                    sender.acknowledged(&address, session, sequence);
+                    continue;
+                }
+            },
+            _ = retransmit.tick() => {
+                let reliable::Due { resend, abandoned } = sender.due();
+                for (address, sequence) in abandoned {
+                    warn!(peer = %address, sequence, "gave up on unacknowledged frame");
+                }
+                for (address, envelope) in resend {
+                    trace!(peer = %address, "retransmitting");
+                    send_envelope(&socket, address, envelope, mtu, &mut next_id).await;
+                }
+                continue;
+            }
+        };
+        send_envelope(&socket, address, envelope, mtu, &mut next_id).await;
+    }
+}
+
+async fn send_envelope(
+    socket: &UdpSocket,
+    address: SocketAddr,
+    envelope: Bytes,
+    mtu: usize,
+    next_id: &mut FragmentId,
+) {
+    let id = *next_id;
+    *next_id = id.wrapping_add(1);
+
+    let fragments = match fragment::fragment(id, &envelope, mtu) {
+        Some(fragments) => fragments,
+        None => {
+            warn!(peer = %address, bytes = envelope.len(), "frame is too large to send");
+            return;
         }
+    };
+    match send_fragments(socket, address, fragments).await {
+        Ok(()) => trace!(peer = %address, bytes = envelope.len(), "sent"),
+        Err(err) => warn!(peer = %address, error = %err, "send failed"),
     }
 }
 
-fn addr_str(address: SocketAddr) -> String {
-    format!("ip{{{}}}->udp{{{}}}", address.ip(), address.port(),)
+async fn send_fragments(
+    socket: &UdpSocket,
+    address: SocketAddr,
+    fragments: Vec<Bytes>,
+) -> io::Result<()> {
+    for datagram in fragments {
+        socket.send_to(datagram.as_ref(), address).await?;
+    }
+    Ok(())
 }
@@ -0,0 +1,412 @@
+use super::connections::{self, framed, Acceptor, Framed};
+use super::{
+    events, queue, receive_space, try_queue, unix_address, FrameRx, FrameTx, Inbound, InboundTx,
+    Queues, TransportFrame,
+};
+use crate::transport::{
+    Delivery, PeerAddress, Transport, TransportEvents, TransportProtocol, DEFAULT_BUFFER_SIZE,
+    UNIX_DATAGRAM_SCHEME, UNIX_STREAM_SCHEME,
+};
+use crate::RelayError;
+use bytes::{Bytes, BytesMut};
+use futures::{
+    future::{self, BoxFuture, FutureExt},
+    SinkExt, StreamExt,
+};
+use std::{
+    ffi::OsStr,
+    io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    net::{UnixDatagram, UnixListener, UnixStream},
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, debug_span, trace, warn, Instrument};
+
+// the largest frame we send or receive over a unix datagram socket, well
+// under what linux accepts by default
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+/*
+
+    Unix sockets that connect without binding first are unnamed, and there
+    is no path we could ever send a reply to. So a dialer always opens a
+    stream with a preamble frame holding the path of its own listener, and
+    the accepting side files every frame received on it under that path.
+    Nothing proves the dialer listens there, so we never send over a
+    connection we accepted: frames for a path always go out on a
+    connection we dialed ourselves, which reaches whoever is listening at
+    that path. Connections we accepted are only ever read from, and are
+    kept apart by their id, so claiming someone else's path neither
+    replaces their connection nor steers replies away from them. Only the
+    connections we dialed report connects and disconnects.
+
+    Datagrams are sent from our bound socket, so the receiver sees our path
+    directly. Anything arriving from an unnamed socket is dropped. Frames
+    aren't split over several datagrams, so a frame has to fit in one of
+    `MAX_DATAGRAM` bytes; we receive room for that much, and refuse to send
+    anything larger rather than have it truncated on the way in.
+
+*/
+
+pub(crate) struct UnixStreamTransport {
+    path: PathBuf,
+    queues: Queues,
+    out_frame_tx: Option<FrameTx<PathBuf>>,
+    disconnect_tx: Option<UnboundedSender<PathBuf>>,
+    shutdown: CancellationToken,
+    task: Option<JoinHandle<()>>,
+}
+impl UnixStreamTransport {
+    pub(crate) fn new(path: PathBuf, queues: Queues) -> UnixStreamTransport {
+        UnixStreamTransport {
+            path,
+            queues,
+            out_frame_tx: None,
+            disconnect_tx: None,
+            shutdown: CancellationToken::new(),
+            task: None,
+        }
+    }
+}
+impl Transport for UnixStreamTransport {
+    fn scheme(&self) -> &str {
+        UNIX_STREAM_SCHEME
+    }
+
+    fn bind(&mut self) -> BoxFuture<'_, Result<TransportEvents, RelayError>> {
+        async move {
+            let listener = UnixListener::bind(&self.path)?;
+
+            let (inbound_tx, inbound_rx) = mpsc::channel(self.queues.capacity);
+            let (out_frame_tx, out_frame_rx) = mpsc::channel(self.queues.capacity);
+            let (disconnect_tx, disconnect_rx) = mpsc::unbounded_channel();
+            self.task = Some(tokio::spawn(listen_stream(
+                listener,
+                self.path.clone(),
+                self.queues.clone(),
+                inbound_tx,
+                out_frame_rx,
+                disconnect_rx,
+                self.shutdown.clone(),
+            )));
+            self.out_frame_tx = Some(out_frame_tx);
+            self.disconnect_tx = Some(disconnect_tx);
+            Ok(events(inbound_rx, unix_address(TransportProtocol::Stream)))
+        }
+        .boxed()
+    }
+
+    fn send(
+        &self,
+        address: PeerAddress,
+        bytes: Bytes,
+        _delivery: Delivery,
+    ) -> BoxFuture<'static, Result<(), RelayError>> {
+        match peer_path(&address) {
+            Some(path) => queue(
+                &self.out_frame_tx,
+                TransportFrame {
+                    address: path,
+                    bytes,
+                },
+            ),
+            None => future::ready(Err(RelayError::UnsupportedAddress(address))).boxed(),
+        }
+    }
+
+    fn try_send(
+        &self,
+        address: PeerAddress,
+        bytes: Bytes,
+        _delivery: Delivery,
+    ) -> Result<(), RelayError> {
+        match peer_path(&address) {
+            Some(path) => try_queue(
+                &self.out_frame_tx,
+                address,
+                TransportFrame {
+                    address: path,
+                    bytes,
+                },
+            ),
+            None => Err(RelayError::UnsupportedAddress(address)),
+        }
+    }
+
+    fn disconnect(&self, address: PeerAddress) {
+        if let (Some(path), Some(disconnect_tx)) = (peer_path(&address), &self.disconnect_tx) {
+            let _ = disconnect_tx.send(path);
+        }
+    }
+
+    fn shutdown(&mut self) -> BoxFuture<'_, ()> {
+        async move {
+            self.out_frame_tx = None;
+            self.disconnect_tx = None;
+            self.shutdown.cancel();
+            if let Some(task) = self.task.take() {
+                let _ = task.await;
+                remove_socket_file(&self.path);
+            }
+        }
+        .boxed()
+    }
+}
+
+pub(crate) struct UnixDatagramTransport {
+    path: PathBuf,
+    queue_capacity: usize,
+    out_frame_tx: Option<FrameTx<PathBuf>>,
+    task: Option<JoinHandle<()>>,
+}
+impl UnixDatagramTransport {
+    pub(crate) fn new(path: PathBuf, queue_capacity: usize) -> UnixDatagramTransport {
+        UnixDatagramTransport {
+            path,
+            queue_capacity,
+            out_frame_tx: None,
+            task: None,
+        }
+    }
+}
+impl Transport for UnixDatagramTransport {
+    fn scheme(&self) -> &str {
+        UNIX_DATAGRAM_SCHEME
+    }
+
+    fn bind(&mut self) -> BoxFuture<'_, Result<TransportEvents, RelayError>> {
+        async move {
+            let socket = Arc::new(UnixDatagram::bind(&self.path)?);
+
+            let (inbound_tx, inbound_rx) = mpsc::channel(self.queue_capacity);
+            let (out_frame_tx, out_frame_rx) = mpsc::channel(self.queue_capacity);
+            self.task = Some(tokio::spawn(listen_datagram(
+                socket,
+                self.path.clone(),
+                inbound_tx,
+                out_frame_rx,
+            )));
+            self.out_frame_tx = Some(out_frame_tx);
+            Ok(events(
+                inbound_rx,
+                unix_address(TransportProtocol::Datagram),
+            ))
+        }
+        .boxed()
+    }
+
+    fn send(
+        &self,
+        address: PeerAddress,
+        bytes: Bytes,
+        delivery: Delivery,
+    ) -> BoxFuture<'static, Result<(), RelayError>> {
+        if let Err(err) = check_datagram(&address, &bytes, delivery) {
+            return future::ready(Err(err)).boxed();
+        }
+        match peer_path(&address) {
+            Some(path) => queue(
+                &self.out_frame_tx,
+                TransportFrame {
+                    address: path,
+                    bytes,
+                },
+            ),
+            None => future::ready(Err(RelayError::UnsupportedAddress(address))).boxed(),
+        }
+    }
+
+    fn try_send(
+        &self,
+        address: PeerAddress,
+        bytes: Bytes,
+        delivery: Delivery,
+    ) -> Result<(), RelayError> {
+        check_datagram(&address, &bytes, delivery)?;
+        match peer_path(&address) {
+            Some(path) => try_queue(
+                &self.out_frame_tx,
+                address,
+                TransportFrame {
+                    address: path,
+                    bytes,
+                },
+            ),
+            None => Err(RelayError::UnsupportedAddress(address)),
+        }
+    }
+
+    fn disconnect(&self, _address: PeerAddress) {} // no connections to drop
+
+    fn shutdown(&mut self) -> BoxFuture<'_, ()> {
+        async move {
+            self.out_frame_tx = None;
+            if let Some(task) = self.task.take() {
+                let _ = task.await;
+                remove_socket_file(&self.path);
+            }
+        }
+        .boxed()
+    }
+}
+
+// datagrams are only ever sent best effort, and in one piece
+fn check_datagram(
+    address: &PeerAddress,
+    bytes: &Bytes,
+    delivery: Delivery,
+) -> Result<(), RelayError> {
+    if delivery == Delivery::Reliable {
+        return Err(RelayError::UnsupportedDelivery(address.clone()));
+    }
+    if bytes.len() > MAX_DATAGRAM {
+        return Err(RelayError::FrameTooLarge(bytes.len()));
+    }
+    Ok(())
+}
+
+// we can't reach unnamed peers
+fn peer_path(address: &PeerAddress) -> Option<PathBuf> {
+    match address {
+        PeerAddress::Unix { address, .. } => address.as_pathname().map(Path::to_path_buf),
+        _ => None,
+    }
+}
+
+// unix sockets leave their files behind, clean them up so the paths can be
+// bound again
+fn remove_socket_file(path: &Path) {
+    if let Err(err) = std::fs::remove_file(path) {
+        warn!(path = %path.display(), error = %err, "couldn't remove socket file");
+    }
+}
+
+// see `connections` for how connections are handled once accepted or dialed
+async fn listen_stream(
+    listener: UnixListener,
+    local: PathBuf,
+    queues: Queues,
+    inbound_tx: InboundTx<PathBuf>,
+    out_frame_rx: FrameRx<PathBuf>,
+    disconnect_rx: UnboundedReceiver<PathBuf>,
+    shutdown: CancellationToken,
+) {
+    let span = debug_span!("unix_stream", local = %local.display());
+    let preamble = Bytes::copy_from_slice(local.as_os_str().as_bytes());
+    connections::run(
+        |acceptor| accept_connections(listener, acceptor),
+        |path| connect(path.clone(), preamble.clone()).boxed(),
+        queues,
+        inbound_tx,
+        out_frame_rx,
+        disconnect_rx,
+        shutdown,
+    )
+    .instrument(span)
+    .await
+}
+
+async fn accept_connections(listener: UnixListener, acceptor: Acceptor<PathBuf>) {
+    loop {
+        match listener.accept().await {
+            Ok((unix, _)) => {
+                let connection = accept_connection(unix, acceptor.clone());
+                tokio::spawn(connection.in_current_span());
+            }
+            Err(err) => warn!(error = %err, "couldn't accept client"),
+        }
+    }
+}
+
+async fn accept_connection(unix: UnixStream, acceptor: Acceptor<PathBuf>) {
+    let mut framed = framed(unix);
+    // the first frame tells us where the peer is listening
+    let preamble = tokio::select! {
+        preamble = framed.next() => preamble,
+        () = acceptor.cancelled() => return,
+    };
+    let address = match preamble {
+        Some(Ok(bytes)) if !bytes.is_empty() => PathBuf::from(OsStr::from_bytes(&bytes)),
+        Some(Ok(_)) => return warn!("dropping connection from unnamed peer"),
+        Some(Err(err)) => return warn!(error = %err, "couldn't read preamble"),
+        None => return,
+    };
+    acceptor.read_only(address, framed).await
+}
+
+// dial `path`, telling whoever listens there where we do
+async fn connect(path: PathBuf, preamble: Bytes) -> io::Result<Framed<UnixStream>> {
+    let mut framed = framed(UnixStream::connect(&path).await?);
+    framed.send(preamble).await?;
+    Ok(framed)
+}
+
+async fn listen_datagram(
+    socket: Arc<UnixDatagram>,
+    local: PathBuf,
+    inbound_tx: InboundTx<PathBuf>,
+    out_frame_rx: FrameRx<PathBuf>,
+) {
+    let span = debug_span!("unix_datagram", local = %local.display());
+    let sender = socket;
+    let listener = sender.clone();
+
+    async move {
+        debug!("listening");
+        tokio::select! {
+            () = handle_incoming_datagrams(listener, inbound_tx) => {},
+            () = handle_outgoing_datagrams(out_frame_rx, sender) => {},
+        };
+        debug!("stopped");
+    }
+    .instrument(span)
+    .await
+}
+
+async fn handle_incoming_datagrams(listener: Arc<UnixDatagram>, inbound_tx: InboundTx<PathBuf>) {
+    let mut buf = BytesMut::new();
+    loop {
+        // datagrams are split off the buffer rather than copied out of it
+        match listener
+            .recv_from(receive_space(&mut buf, MAX_DATAGRAM, DEFAULT_BUFFER_SIZE))
+            .await
+        {
+            Ok((len, address)) => {
+                let address = match address.as_pathname() {
+                    Some(path) => path.to_path_buf(),
+                    None => {
+                        warn!(bytes = len, "dropping datagram from unnamed peer");
+                        continue;
+                    }
+                };
+                let bytes = buf.split_to(len).freeze();
+                let peer = address.display().to_string();
+                match inbound_tx
+                    .send(Inbound::Frame(TransportFrame { address, bytes }))
+                    .await
+                {
+                    Ok(()) => trace!(%peer, bytes = len, "received"),
+                    Err(_) => warn!(%peer, bytes = len, "frame receiver is closed"),
+                }
+            }
+            Err(err) => warn!(error = %err, "receive failed"),
+        }
+    }
+}
+
+async fn handle_outgoing_datagrams(mut out_frame_rx: FrameRx<PathBuf>, sender: Arc<UnixDatagram>) {
+    while let Some(message) = out_frame_rx.recv().await {
+        let TransportFrame { address, bytes } = message;
+        let peer = Path::display(&address);
+        match sender.send_to(bytes.as_ref(), &address).await {
+            Ok(len) => trace!(%peer, bytes = len, "sent"),
+            Err(err) => warn!(%peer, error = %err, "send failed"),
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use crate::codec::Codec;
+use crate::message::Message;
+use crate::transport::PeerAddress;
+use crate::RelayError;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/*
+
+    Every relay frame starts with a four byte header: two magic bytes, the
+    wire version the rest of the frame is encoded with, and flags. A node
+    speaks every version from `MIN_WIRE_VERSION` up to `WIRE_VERSION`, and
+    decodes each frame with the version it was sent with, so peers on any
+    of those keep working. Frames with a version we don't speak are
+    rejected, and so are frames without a header: nodes from before the
+    header existed can't talk to us.
+
+    The top four bits of the flags name the codec the message was encoded
+    with, and payloads are byte strings in every codec. Once a peer we've
+    exchanged versions with sends us a message, we send to it with the
+    codec it used rather than ours, since that's one we know it decodes.
+
+    Which version we send to a peer is negotiated. The first time we send
+    to an address we lead with a versions frame holding the range we speak,
+    and the peer answers with its own; both sides then use the newest
+    version they have in common. Until that answer arrives we send with
+    the oldest version we speak. A versions frame is only ever a header
+    with the versions flag and two bytes of body, that can never change.
+    A frame with a version we don't speak is answered with our versions
+    too, but only so often, since anyone can send us one with someone
+    else's address on it.
+
+*/
+
+pub type WireVersion = u8;
+
+// the newest and oldest versions of the wire format we speak
+pub const WIRE_VERSION: WireVersion = 1;
+pub const MIN_WIRE_VERSION: WireVersion = 1;
+
+const CODEC_SHIFT: u8 = 4;
+
+const MAGIC: [u8; 2] = *b"RL";
+const HEADER_LEN: usize = 4;
+
+// flags, unknown ones are ignored
+const FLAG_VERSIONS: u8 = 1 << 0; // the body is the range of versions the sender speaks
+const FLAG_REPLY: u8 = 1 << 1; // answers a versions frame, don't answer it back
+
+// frames in versions we don't speak are answered once per interval for each
+// address, and only so many times per interval in all
+const REANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+const REANNOUNCE_LIMIT: usize = 16;
+
+pub(crate) enum Frame {
+    Message(Message, Codec),
+    Versions {
+        min: WireVersion,
+        max: WireVersion,
+        reply: bool,
+    },
+}
+
+pub(crate) fn encode(
+    message: &Message,
+    version: WireVersion,
+    codec: Codec,
+) -> Result<Bytes, RelayError> {
+    if !(MIN_WIRE_VERSION..=WIRE_VERSION).contains(&version) {
+        return Err(RelayError::UnsupportedVersion(version));
+    }
+    let flags = codec.id() << CODEC_SHIFT;
+    let body = codec.encode(message)?;
+    let mut frame = BytesMut::with_capacity(HEADER_LEN + body.len());
+    put_header(&mut frame, version, flags);
+    frame.put(body);
+    Ok(frame.freeze())
+}
+
+pub(crate) fn decode(mut frame: Bytes) -> Result<Frame, RelayError> {
+    if frame.len() < HEADER_LEN || frame[..MAGIC.len()] != MAGIC {
+        return Err(RelayError::Decode("missing frame header".to_string()));
+    }
+    let header = frame.split_to(HEADER_LEN);
+    let (version, flags) = (header[2], header[3]);
+
+    if flags & FLAG_VERSIONS != 0 {
+        return match frame[..] {
+            [min, max, ..] => Ok(Frame::Versions {
+                min,
+                max,
+                reply: flags & FLAG_REPLY != 0,
+            }),
+            _ => Err(RelayError::Decode("truncated versions frame".to_string())),
+        };
+    }
+    if !(MIN_WIRE_VERSION..=WIRE_VERSION).contains(&version) {
+        return Err(RelayError::UnsupportedVersion(version));
+    }
+    let codec = match Codec::from_id(flags >> CODEC_SHIFT) {
+        Some(codec) => codec,
+        None => return Err(RelayError::Decode("unknown codec".to_string())),
+    };
+    let message = codec.decode(frame)?;
+    Ok(Frame::Message(message, codec))
+}
+
+fn versions_frame(reply: bool) -> Bytes {
+    let flags = if reply {
+        FLAG_VERSIONS | FLAG_REPLY
+    } else {
+        FLAG_VERSIONS
+    };
+    let mut frame = BytesMut::with_capacity(HEADER_LEN + 2);
+    put_header(&mut frame, WIRE_VERSION, flags);
+    frame.put_u8(MIN_WIRE_VERSION);
+    frame.put_u8(WIRE_VERSION);
+    frame.freeze()
+}
+
+fn put_header(frame: &mut BytesMut, version: WireVersion, flags: u8) {
+    frame.put_slice(&MAGIC);
+    frame.put_u8(version);
+    frame.put_u8(flags);
+}
+
+#[derive(Default)]
+struct Peer {
+    announced: bool,
+    version: Option<WireVersion>,
+    // the codec the peer last sent with
+    codec: Option<Codec>,
+}
+
+#[derive(Default)]
+pub(crate) struct Versions {
+    peers: HashMap<PeerAddress, Peer>,
+    // where we reannounced to since `window` started
+    reannounced: HashSet<PeerAddress>,
+    window: Option<Instant>,
+}
+impl Versions {
+    // the versions frame to send ahead of the first frame to `address`
+    pub(crate) fn announce(&mut self, address: &PeerAddress) -> Option<Bytes> {
+        let peer = self.peers.entry(address.clone()).or_default();
+        if peer.announced {
+            return None;
+        }
+        peer.announced = true;
+        Some(versions_frame(false))
+    }
+
+    // same as `announce`, but for when the peer sent us a version we don't
+    // speak; it'll answer with the versions it does speak. `None` when
+    // we've answered too much lately
+    pub(crate) fn reannounce(&mut self, address: &PeerAddress) -> Option<Bytes> {
+        let now = Instant::now();
+        if self
+            .window
+            .is_none_or(|window| now.duration_since(window) >= REANNOUNCE_INTERVAL)
+        {
+            self.window = Some(now);
+            self.reannounced.clear();
+        }
+        if self.reannounced.len() >= REANNOUNCE_LIMIT || !self.reannounced.insert(address.clone()) {
+            return None;
+        }
+        self.peers.entry(address.clone()).or_default().announced = true;
+        Some(versions_frame(false))
+    }
+
+    // settle on the newest version both sides speak, `None` if there isn't
+    // one; the second value is what to answer the peer with
+    pub(crate) fn negotiate(
+        &mut self,
+        address: &PeerAddress,
+        min: WireVersion,
+        max: WireVersion,
+        reply: bool,
+    ) -> (Option<WireVersion>, Option<Bytes>) {
+        let peer = self.peers.entry(address.clone()).or_default();
+        let version = Some(max.min(WIRE_VERSION)).filter(|v| *v >= min.max(MIN_WIRE_VERSION));
+        peer.version = version;
+        if reply {
+            return (version, None);
+        }
+        peer.announced = true;
+        (version, Some(versions_frame(true)))
+    }
+
+    // `address` sent us a message encoded with `codec`; only peers we've
+    // exchanged versions with are kept track of, so this never grows the
+    // table
+    pub(crate) fn heard(&mut self, address: &PeerAddress, codec: Codec) {
+        if let Some(peer) = self.peers.get_mut(address) {
+            peer.codec = Some(codec);
+        }
+    }
+
+    // the version and codec to send frames to `address` with, `codec`
+    // until the peer has sent us one of its own
+    pub(crate) fn format(&self, address: &PeerAddress, codec: Codec) -> (WireVersion, Codec) {
+        match self.peers.get(address) {
+            Some(peer) => (
+                peer.version.unwrap_or(MIN_WIRE_VERSION),
+                peer.codec.unwrap_or(codec),
+            ),
+            None => (MIN_WIRE_VERSION, codec),
+        }
+    }
+
+    pub(crate) fn remove(&mut self, address: &PeerAddress) {
+        self.peers.remove(address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_name_their_codec_and_carry_payloads_as_byte_strings() {
+        let message = Message::ConnectionMessage {
+            key: 7,
+            payload: Bytes::from_static(b"payload"),
+        };
+        for codec in [Codec::MessagePack, Codec::Compact, Codec::Cbor] {
+            let frame = encode(&message, WIRE_VERSION, codec).unwrap();
+            assert_eq!(
+                frame[..HEADER_LEN],
+                [b'R', b'L', 1, codec.id() << CODEC_SHIFT]
+            );
+            // the payload shows up as-is, not as a list of integers
+            assert!(frame.windows(7).any(|window| window == b"payload"));
+            match decode(frame) {
+                Ok(Frame::Message(Message::ConnectionMessage { key: 7, payload }, decoded)) => {
+                    assert_eq!(decoded, codec);
+                    assert_eq!(payload, Bytes::from_static(b"payload"))
+                }
+                _ => panic!("{:?} frame didn't round trip", codec),
+            }
+        }
+    }
+
+    #[test]
+    fn frames_in_versions_we_dont_speak_are_rejected() {
+        let message = Message::Ping;
+        assert!(matches!(
+            encode(&message, WIRE_VERSION + 1, Codec::MessagePack),
+            Err(RelayError::UnsupportedVersion(_))
+        ));
+        let mut frame =
+            BytesMut::from(&encode(&message, WIRE_VERSION, Codec::MessagePack).unwrap()[..]);
+        frame[2] = WIRE_VERSION + 1;
+        assert!(matches!(
+            decode(frame.freeze()),
+            Err(RelayError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn peers_are_answered_with_the_codec_they_send_with() {
+        let address = PeerAddress::Memory {
+            name: "peer".to_string(),
+        };
+        let mut versions = Versions::default();
+
+        // a peer we haven't exchanged versions with isn't tracked
+        versions.heard(&address, Codec::Compact);
+        assert_eq!(
+            versions.format(&address, Codec::MessagePack),
+            (MIN_WIRE_VERSION, Codec::MessagePack)
+        );
+
+        versions.negotiate(&address, MIN_WIRE_VERSION, WIRE_VERSION, false);
+        assert_eq!(
+            versions.format(&address, Codec::MessagePack),
+            (WIRE_VERSION, Codec::MessagePack)
+        );
+        versions.heard(&address, Codec::Compact);
+        assert_eq!(
+            versions.format(&address, Codec::MessagePack),
+            (WIRE_VERSION, Codec::Compact)
+        );
+    }
+}
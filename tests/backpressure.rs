@@ -0,0 +1,44 @@
+mod common;
+
+use common::peer;
+use relay_protocol::{Delivery, MemoryNetwork, Message, RelayError};
+use std::time::Duration;
+use tokio::time;
+
+#[tokio::test]
+async fn memory_transports_refuse_frames_once_the_peer_is_backed_up() {
+    let network = MemoryNetwork::new();
+    let mut a = peer(&network, "a", &[], |builder| builder).await;
+    let b = peer(&network, "b", &[], |builder| builder.queue_capacity(4)).await;
+
+    // b isn't listening, so nothing drains its queue
+    for _ in 0..8 {
+        match a.node.send(b.address.clone(), Message::Ping) {
+            Ok(()) => {}
+            Err(RelayError::QueueFull(_)) => return,
+            Err(err) => panic!("expected the queue to fill up, got {:?}", err),
+        }
+    }
+    panic!("the queue never filled up");
+}
+
+#[tokio::test]
+async fn handles_wait_for_the_node_once_its_queue_is_full() {
+    let network = MemoryNetwork::new();
+    let a = peer(&network, "a", &[], |builder| builder.queue_capacity(1)).await;
+    let handle = a.node.handle();
+
+    handle
+        .set_delivery(a.address.clone(), Delivery::BestEffort)
+        .await;
+    let waiting = handle.set_delivery(a.address.clone(), Delivery::Reliable);
+    tokio::pin!(waiting);
+    let wait = Duration::from_millis(100);
+    assert!(time::timeout(wait, &mut waiting).await.is_err());
+
+    // the node catching up makes room
+    let _a = a.node.spawn();
+    time::timeout(Duration::from_secs(5), waiting)
+        .await
+        .unwrap();
+}
@@ -0,0 +1,180 @@
+// every test file only uses some of these
+#![allow(dead_code)]
+
+use relay_protocol::{
+    Delegate, MemoryNetwork, MessageId, Node, NodeBuilder, NodeHandle, PageCount, Payload,
+    PeerAddress, PeerId, ProtocolHandler, ProtocolId, PublicKey, TransportProtocol,
+};
+use std::os::unix::net::SocketAddr as UnixSocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time;
+
+pub const PROTOCOL: &[u8] = b"echo";
+
+// what the delegate and protocol handlers saw, in the order they saw it
+#[derive(Debug)]
+pub enum Event {
+    Message(PeerId, Payload),
+    Requested(Payload),
+    Accepted(Payload),
+    Closed(Payload),
+    Lost,
+    Secured(PublicKey),
+}
+
+struct Reporter(UnboundedSender<Event>);
+// negotiations in these tests are all followed up on by the node
+impl Delegate for Reporter {
+    fn handle_negotiated_protocol(&self, _: PeerAddress, _: MessageId, _: ProtocolId) {}
+
+    fn handle_negotiation_failure(&self, _: PeerAddress, _: MessageId, _: PageCount) {}
+}
+impl ProtocolHandler for Reporter {
+    fn handle_message(&self, peer: PeerId, _: PeerAddress, payload: Payload) {
+        let _ = self.0.send(Event::Message(peer, payload));
+    }
+
+    fn verify_requested_connection(
+        &self,
+        _: PeerId,
+        _: PeerAddress,
+        payload: Payload,
+    ) -> Option<Payload> {
+        let _ = self.0.send(Event::Requested(payload.clone()));
+        Some(payload)
+    }
+
+    fn verify_accepted_connection(
+        &self,
+        _: PeerId,
+        _: PeerAddress,
+        payload: Payload,
+    ) -> Option<Payload> {
+        let _ = self.0.send(Event::Accepted(payload.clone()));
+        Some(payload)
+    }
+
+    fn verify_confirmed_connection(&self, _: PeerId, _: PeerAddress, _: Payload) -> bool {
+        true
+    }
+
+    fn verify_closed_connection(&self, _: PeerId, _: PeerAddress, payload: Payload) -> bool {
+        let _ = self.0.send(Event::Closed(payload));
+        true
+    }
+
+    fn connection_lost(&self, _: PeerId, _: PeerAddress) {
+        let _ = self.0.send(Event::Lost);
+    }
+
+    fn secured(&self, _: PeerId, _: PeerAddress, remote_key: PublicKey) {
+        let _ = self.0.send(Event::Secured(remote_key));
+    }
+}
+
+pub struct Peer {
+    pub node: Node,
+    pub address: PeerAddress,
+    pub events: UnboundedReceiver<Event>,
+}
+
+// a node that binds no sockets unless `configure` asks it to, and speaks
+// `protocols`; `address` is where other nodes reach it
+pub async fn node(
+    address: PeerAddress,
+    protocols: &[&[u8]],
+    configure: impl FnOnce(NodeBuilder) -> NodeBuilder,
+) -> Peer {
+    let (events_tx, events) = mpsc::unbounded_channel();
+    let builder = Node::builder(Box::new(Reporter(events_tx.clone())))
+        .without_tcp()
+        .without_udp();
+    let mut node = configure(builder).build().await.unwrap();
+    for id in protocols {
+        node.register_protocol(id.to_vec(), Box::new(Reporter(events_tx.clone())))
+            .unwrap();
+    }
+    Peer {
+        node,
+        address,
+        events,
+    }
+}
+
+// a node on `network`, reached by `name`
+pub async fn peer(
+    network: &MemoryNetwork,
+    name: &str,
+    protocols: &[&[u8]],
+    configure: impl FnOnce(NodeBuilder) -> NodeBuilder,
+) -> Peer {
+    let transport = network.transport(name);
+    let address = transport.address();
+    node(address, protocols, |builder| {
+        configure(builder.transport(Box::new(transport)))
+    })
+    .await
+}
+
+// somewhere to bind a unix socket for `test`, which must be unique
+pub fn socket_path(test: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("relay-{}-{}", std::process::id(), test));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+// a node listening on a unix socket of its own, named for `test`
+pub async fn unix_peer(
+    test: &str,
+    protocol: TransportProtocol,
+    configure: impl FnOnce(NodeBuilder) -> NodeBuilder,
+) -> Peer {
+    let path = socket_path(test);
+    let address = PeerAddress::Unix {
+        address: UnixSocketAddr::from_pathname(&path).unwrap(),
+        protocol: protocol.clone(),
+    };
+    node(address, &[PROTOCOL], |builder| {
+        configure(match protocol {
+            TransportProtocol::Datagram => builder.unix_datagram_path(path),
+            TransportProtocol::Stream => builder.unix_stream_path(path),
+        })
+    })
+    .await
+}
+
+pub async fn next(events: &mut UnboundedReceiver<Event>) -> Event {
+    match time::timeout(Duration::from_secs(5), events.recv()).await {
+        Ok(Some(event)) => event,
+        Ok(None) => panic!("handlers are gone"),
+        Err(_) => panic!("timed out waiting for an event"),
+    }
+}
+
+// connects to `PROTOCOL` at `address`, and waits for the peer to see it
+// and for its answer to come back
+pub async fn connect(
+    handle: &NodeHandle,
+    address: &PeerAddress,
+    ours: &mut UnboundedReceiver<Event>,
+    theirs: &mut UnboundedReceiver<Event>,
+) {
+    handle
+        .connect(
+            address.clone(),
+            PROTOCOL.to_vec(),
+            Payload::from_static(b"hi"),
+        )
+        .await
+        .unwrap();
+    match next(theirs).await {
+        Event::Requested(payload) => assert_eq!(payload, Payload::from_static(b"hi")),
+        event => panic!("expected the connection request, got {:?}", event),
+    }
+    match next(ours).await {
+        Event::Accepted(payload) => assert_eq!(payload, Payload::from_static(b"hi")),
+        event => panic!("expected the connection to be accepted, got {:?}", event),
+    }
+}
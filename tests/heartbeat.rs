@@ -0,0 +1,31 @@
+mod common;
+
+use common::{connect, next, peer, Event, PROTOCOL};
+use relay_protocol::{MemoryNetwork, NodeBuilder};
+use std::time::Duration;
+use tokio::time;
+
+#[tokio::test]
+async fn heartbeats_expire_a_peer_that_stopped_answering() {
+    let network = MemoryNetwork::new();
+    let heartbeat = |builder: NodeBuilder| {
+        builder.heartbeat(Duration::from_millis(50), Duration::from_millis(200))
+    };
+    let mut a = peer(&network, "a", &[PROTOCOL], heartbeat).await;
+    let mut b = peer(&network, "b", &[PROTOCOL], |builder| builder).await;
+    let handle = a.node.spawn();
+    // run b where it can be stopped without shutting its transport down, so
+    // it goes quiet rather than disconnecting
+    let running = tokio::spawn(async move { b.node.listen().await });
+
+    connect(&handle, &b.address, &mut a.events, &mut b.events).await;
+    // pings keep a peer that answers them connected
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(a.events.try_recv().is_err());
+
+    running.abort();
+    match next(&mut a.events).await {
+        Event::Lost => {}
+        event => panic!("expected the peer to be lost, got {:?}", event),
+    }
+}
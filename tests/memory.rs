@@ -0,0 +1,43 @@
+mod common;
+
+use common::{connect, next, peer, Event, PROTOCOL};
+use relay_protocol::{MemoryNetwork, Payload, RelayError};
+
+#[tokio::test]
+async fn memory_only_nodes_bind_no_sockets() {
+    let network = MemoryNetwork::new();
+    let a = peer(&network, "a", &[PROTOCOL], |builder| builder).await;
+    assert!(a.node.tcp_address().is_none());
+    assert!(a.node.udp_address().is_none());
+    a.node.spawn().shutdown().await;
+}
+
+#[tokio::test]
+async fn close_ends_the_protocol_connection() {
+    let network = MemoryNetwork::new();
+    let mut a = peer(&network, "a", &[PROTOCOL], |builder| builder).await;
+    let mut b = peer(&network, "b", &[PROTOCOL], |builder| builder).await;
+    let handle = a.node.spawn();
+    let _b = b.node.spawn();
+
+    connect(&handle, &b.address, &mut a.events, &mut b.events).await;
+    handle
+        .close(
+            b.address.clone(),
+            PROTOCOL.to_vec(),
+            Payload::from_static(b"bye"),
+        )
+        .await
+        .unwrap();
+    match next(&mut b.events).await {
+        Event::Closed(payload) => assert_eq!(payload, Payload::from_static(b"bye")),
+        event => panic!("expected the close, got {:?}", event),
+    }
+    match handle
+        .send(b.address.clone(), PROTOCOL.to_vec(), Payload::new())
+        .await
+    {
+        Err(RelayError::NotConnected(_)) => {}
+        result => panic!("expected to be disconnected, got {:?}", result),
+    }
+}
@@ -0,0 +1,77 @@
+mod common;
+
+use common::{next, peer, Event, PROTOCOL};
+use relay_protocol::{MemoryNetwork, NegotiationOutcome, Payload};
+
+#[tokio::test]
+async fn negotiation_sends_a_payload_left_out_of_the_mask() {
+    let network = MemoryNetwork::new();
+    let a = peer(&network, "a", &[], |builder| builder).await;
+    let mut b = peer(&network, "b", &[b"proto-2"], |builder| builder).await;
+    let a = a.node.spawn();
+    let _b = b.node.spawn();
+
+    // both share a page, which only carries proto-1's payload
+    let proposals = vec![
+        (b"proto-1".to_vec(), Some(Payload::from_static(b"for one"))),
+        (b"proto-2".to_vec(), Some(Payload::from_static(b"for two"))),
+    ];
+    let outcome = a
+        .send_negotiable(b.address.clone(), proposals)
+        .await
+        .unwrap();
+    assert_eq!(outcome, NegotiationOutcome::Negotiated(b"proto-2".to_vec()));
+    match next(&mut b.events).await {
+        Event::Message(_, payload) => assert_eq!(payload, Payload::from_static(b"for two")),
+        event => panic!("expected the chosen protocol's payload, got {:?}", event),
+    }
+}
+#[tokio::test]
+async fn negotiation_pages_through_proposals_to_the_inline_payload() {
+    let network = MemoryNetwork::new();
+    // small pages, so the proposals span several of them
+    let a = peer(&network, "a", &[], |builder| {
+        builder.negotiation_page_size(8)
+    })
+    .await;
+    let mut b = peer(&network, "b", &[b"proto-7"], |builder| builder).await;
+    let a = a.node.spawn();
+    let _b = b.node.spawn();
+
+    let proposals = (0..10)
+        .map(|index| {
+            let id = format!("proto-{}", index).into_bytes();
+            // only the proposal b speaks carries a payload, so it's inline
+            let payload = (index == 7).then(|| Payload::from_static(b"for seven"));
+            (id, payload)
+        })
+        .collect();
+    let outcome = a
+        .send_negotiable(b.address.clone(), proposals)
+        .await
+        .unwrap();
+    assert_eq!(outcome, NegotiationOutcome::Negotiated(b"proto-7".to_vec()));
+    match next(&mut b.events).await {
+        Event::Message(_, payload) => assert_eq!(payload, Payload::from_static(b"for seven")),
+        event => panic!("expected the inline payload, got {:?}", event),
+    }
+}
+
+#[tokio::test]
+async fn negotiation_fails_once_every_page_is_turned_down() {
+    let network = MemoryNetwork::new();
+    let a = peer(&network, "a", &[], |builder| {
+        builder.negotiation_page_size(8)
+    })
+    .await;
+    let b = peer(&network, "b", &[PROTOCOL], |builder| builder).await;
+    let b_address = b.address.clone();
+    let a = a.node.spawn();
+    let _b = b.node.spawn();
+
+    let proposals = (0..5)
+        .map(|index| (format!("proto-{}", index).into_bytes(), None))
+        .collect();
+    let outcome = a.send_negotiable(b_address, proposals).await.unwrap();
+    assert_eq!(outcome, NegotiationOutcome::Failed);
+}
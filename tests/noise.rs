@@ -0,0 +1,79 @@
+mod common;
+
+use common::{next, peer, unix_peer, Event, Peer, PROTOCOL};
+use relay_protocol::{Keypair, MemoryNetwork, NodeBuilder, Payload, PeerId, TransportProtocol};
+
+// connects `a` to `b`, checks each learned the other's key before the
+// connection went through, and that what `a` sends `b` comes from it
+async fn handshake(mut a: Peer, mut b: Peer) {
+    let (a_public, b_public) = (
+        a.node.public_key().unwrap().clone(),
+        b.node.public_key().unwrap().clone(),
+    );
+    let b_address = b.address.clone();
+    let a_handle = a.node.spawn();
+    let b_handle = b.node.spawn();
+
+    a_handle
+        .connect(b_address.clone(), PROTOCOL.to_vec(), Payload::new())
+        .await
+        .unwrap();
+    // the handshake finishes before the request it was started for arrives
+    match next(&mut b.events).await {
+        Event::Secured(key) => assert!(key == a_public),
+        event => panic!("expected the handshake to finish, got {:?}", event),
+    }
+    match next(&mut b.events).await {
+        Event::Requested(_) => {}
+        event => panic!("expected the connection request, got {:?}", event),
+    }
+    match next(&mut a.events).await {
+        Event::Secured(key) => assert!(key == b_public),
+        event => panic!("expected the handshake to finish, got {:?}", event),
+    }
+    match next(&mut a.events).await {
+        Event::Accepted(_) => {}
+        event => panic!("expected the connection to be accepted, got {:?}", event),
+    }
+
+    a_handle
+        .send(
+            b_address,
+            PROTOCOL.to_vec(),
+            Payload::from_static(b"sealed"),
+        )
+        .await
+        .unwrap();
+    match next(&mut b.events).await {
+        Event::Message(peer, payload) => {
+            assert_eq!(peer, PeerId::from_public_key(&a_public));
+            assert!(peer.is_verified());
+            assert_eq!(payload, Payload::from_static(b"sealed"));
+        }
+        event => panic!("expected the message, got {:?}", event),
+    }
+    a_handle.shutdown().await;
+    b_handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn handshake_secures_messages_with_the_peers_keys() {
+    let network = MemoryNetwork::new();
+    let a = peer(&network, "a", &[PROTOCOL], |builder| {
+        builder.keypair(Keypair::generate())
+    })
+    .await;
+    let b = peer(&network, "b", &[PROTOCOL], |builder| {
+        builder.keypair(Keypair::generate())
+    })
+    .await;
+    handshake(a, b).await;
+}
+
+#[tokio::test]
+async fn handshake_runs_over_transports_that_cant_deliver_reliably() {
+    let keyed = |builder: NodeBuilder| builder.keypair(Keypair::generate());
+    let a = unix_peer("noise-datagram-a", TransportProtocol::Datagram, keyed).await;
+    let b = unix_peer("noise-datagram-b", TransportProtocol::Datagram, keyed).await;
+    handshake(a, b).await;
+}
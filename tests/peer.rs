@@ -0,0 +1,51 @@
+mod common;
+
+use common::{connect, next, peer, Event, PROTOCOL};
+use relay_protocol::{MemoryNetwork, Message, Payload};
+use std::time::Duration;
+use tokio::time;
+
+#[tokio::test]
+async fn peers_without_a_connection_cant_use_the_protocols_key() {
+    let network = MemoryNetwork::new();
+    let mut a = peer(&network, "a", &[PROTOCOL], |builder| builder).await;
+    let mut b = peer(&network, "b", &[PROTOCOL], |builder| builder).await;
+    let mut c = peer(&network, "c", &[PROTOCOL], |builder| builder).await;
+    let a_handle = a.node.spawn();
+    let b_handle = b.node.spawn();
+    connect(&a_handle, &b.address, &mut a.events, &mut b.events).await;
+
+    // c never connected, but keys are handed out from 1
+    let spoofed = [
+        Message::ConnectionMessage {
+            key: 1,
+            payload: Payload::from_static(b"spoofed"),
+        },
+        Message::ConnectionClosed {
+            key: 1,
+            payload: Payload::from_static(b"spoofed"),
+        },
+    ];
+    for message in spoofed {
+        c.node.send(b.address.clone(), message).unwrap();
+    }
+    time::sleep(Duration::from_millis(200)).await;
+    assert!(b.events.try_recv().is_err());
+
+    // and a is still connected
+    a_handle
+        .send(
+            b.address.clone(),
+            PROTOCOL.to_vec(),
+            Payload::from_static(b"real"),
+        )
+        .await
+        .unwrap();
+    match next(&mut b.events).await {
+        Event::Message(_, payload) => assert_eq!(payload, Payload::from_static(b"real")),
+        event => panic!("expected a's message, got {:?}", event),
+    }
+    assert!(c.events.try_recv().is_err());
+    a_handle.shutdown().await;
+    b_handle.shutdown().await;
+}
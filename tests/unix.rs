@@ -0,0 +1,74 @@
+mod common;
+
+use common::{connect, next, unix_peer, Event, Peer, PROTOCOL};
+use relay_protocol::{Payload, RelayError, TransportProtocol};
+
+async fn datagram_peer(test: &str) -> Peer {
+    unix_peer(test, TransportProtocol::Datagram, |builder| builder).await
+}
+
+async fn stream_peer(test: &str) -> Peer {
+    unix_peer(test, TransportProtocol::Stream, |builder| builder).await
+}
+
+#[tokio::test]
+async fn frames_larger_than_a_udp_buffer_arrive_whole_over_datagrams() {
+    let mut a = datagram_peer("datagram-large-a").await;
+    let mut b = datagram_peer("datagram-large-b").await;
+    let a_handle = a.node.spawn();
+    let b_handle = b.node.spawn();
+
+    connect(&a_handle, &b.address, &mut a.events, &mut b.events).await;
+    let payload = Payload::from(vec![7; 2000]);
+    a_handle
+        .send(b.address.clone(), PROTOCOL.to_vec(), payload.clone())
+        .await
+        .unwrap();
+    match next(&mut b.events).await {
+        Event::Message(_, received) => assert_eq!(received, payload),
+        event => panic!("expected the message, got {:?}", event),
+    }
+    a_handle.shutdown().await;
+    b_handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn frames_too_large_for_a_datagram_are_refused() {
+    let mut a = datagram_peer("datagram-oversize-a").await;
+    let mut b = datagram_peer("datagram-oversize-b").await;
+    let a_handle = a.node.spawn();
+    let b_handle = b.node.spawn();
+
+    connect(&a_handle, &b.address, &mut a.events, &mut b.events).await;
+    let payload = Payload::from(vec![7; 100_000]);
+    match a_handle
+        .send(b.address.clone(), PROTOCOL.to_vec(), payload)
+        .await
+    {
+        Err(RelayError::FrameTooLarge(_)) => {}
+        result => panic!("expected the frame to be refused, got {:?}", result),
+    }
+    a_handle.shutdown().await;
+    b_handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn streams_carry_large_frames() {
+    let mut a = stream_peer("stream-large-a").await;
+    let mut b = stream_peer("stream-large-b").await;
+    let a_handle = a.node.spawn();
+    let b_handle = b.node.spawn();
+
+    connect(&a_handle, &b.address, &mut a.events, &mut b.events).await;
+    let payload = Payload::from(vec![7; 100_000]);
+    a_handle
+        .send(b.address.clone(), PROTOCOL.to_vec(), payload.clone())
+        .await
+        .unwrap();
+    match next(&mut b.events).await {
+        Event::Message(_, received) => assert_eq!(received, payload),
+        event => panic!("expected the message, got {:?}", event),
+    }
+    a_handle.shutdown().await;
+    b_handle.shutdown().await;
+}